@@ -0,0 +1,78 @@
+use std::io::{self, Write};
+
+use viz_core::http::{header, HeaderMap};
+
+/// A content encoding supported for responses.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum Encoding {
+    Br,
+    Gzip,
+}
+
+impl Encoding {
+    /// Picks the preferred encoding of the `Accept-Encoding` header, `br` wins ties.
+    pub(crate) fn negotiate(headers: &HeaderMap) -> Option<Self> {
+        let mut br = None;
+        let mut gzip = None;
+        let mut any = None;
+
+        for value in headers.get_all(header::ACCEPT_ENCODING) {
+            let value = match value.to_str() {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            for item in value.split(',') {
+                let mut parts = item.split(';').map(str::trim);
+                let coding = parts.next().unwrap_or_default();
+                let quality = parts
+                    .find_map(|param| param.strip_prefix("q="))
+                    .map_or(Some(1.0), |q| q.parse::<f32>().ok())
+                    .unwrap_or(0.0);
+
+                match coding.to_ascii_lowercase().as_str() {
+                    "br" => br = Some(quality),
+                    "gzip" | "x-gzip" => gzip = Some(quality),
+                    "*" => any = Some(quality),
+                    _ => {}
+                }
+            }
+        }
+
+        let br = br.or(any).unwrap_or(0.0);
+        let gzip = gzip.or(any).unwrap_or(0.0);
+
+        if br > 0.0 && br >= gzip {
+            Some(Encoding::Br)
+        } else if gzip > 0.0 {
+            Some(Encoding::Gzip)
+        } else {
+            None
+        }
+    }
+
+    /// The value of the `Content-Encoding` header.
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Br => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// Compresses the body.
+    pub(crate) fn compress(self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Br => {
+                // A medium quality, the responses are compressed on the fly.
+                let mut writer = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+                writer.write_all(body)?;
+                Ok(writer.into_inner())
+            }
+            Encoding::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
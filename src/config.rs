@@ -0,0 +1,652 @@
+#[cfg(feature = "uploads")]
+use std::path::PathBuf;
+use std::{sync::Arc, time::Duration};
+
+#[cfg(feature = "uploads")]
+use async_graphql::http::MultipartOptions;
+use async_graphql::http::WebSocketProtocols;
+
+use viz_core::{http::header::HeaderName, Context, Response};
+
+use crate::{
+    rejection::{GraphQLRejection, RejectionHandler},
+    JsonSerializer, OperationRateLimit,
+};
+#[cfg(feature = "uploads")]
+use crate::{TempFileStorage, UploadStorage};
+
+type RejectionCallback = Arc<dyn Fn(&Context, &GraphQLRejection) + Send + Sync>;
+type ResponseCallback = Arc<dyn Fn(&mut Response) + Send + Sync>;
+type RequestIdCallback = Arc<dyn Fn(&Context) -> Option<String> + Send + Sync>;
+
+/// Configuration for the GraphQL extractors.
+///
+/// Insert it into the `Context` extensions, e.g. from a middleware, to tune a route:
+///
+/// ```ignore
+/// cx.extensions_mut().insert(
+///     GraphQLConfig::new().multipart(MultipartOptions::default().max_file_size(1024 * 1024)),
+/// );
+/// ```
+///
+/// The extractors fall back to the default configuration if none is present.
+#[derive(Clone)]
+pub struct GraphQLConfig {
+    #[cfg(feature = "uploads")]
+    pub(crate) multipart: MultipartOptions,
+    pub(crate) max_request_size: u64,
+    #[cfg(feature = "uploads")]
+    pub(crate) max_part_size: usize,
+    #[cfg(feature = "uploads")]
+    pub(crate) max_fields: usize,
+    #[cfg(feature = "uploads")]
+    pub(crate) max_uploads_size: Option<u64>,
+    pub(crate) max_query_len: usize,
+    #[cfg(feature = "compressed_queries")]
+    pub(crate) compressed_query_param: Option<String>,
+    pub(crate) max_batch_len: usize,
+    pub(crate) batches: bool,
+    pub(crate) reject_mutations_over_get: bool,
+    pub(crate) persisted_queries_only: bool,
+    #[cfg(feature = "uploads")]
+    pub(crate) upload_dir: Option<PathBuf>,
+    #[cfg(feature = "uploads")]
+    pub(crate) upload_write_buffer_size: usize,
+    #[cfg(feature = "uploads")]
+    pub(crate) upload_storage: Option<Arc<dyn UploadStorage>>,
+    pub(crate) keep_raw_body: bool,
+    pub(crate) streaming_json: bool,
+    #[cfg(feature = "uploads")]
+    pub(crate) unmapped_files: bool,
+    pub(crate) plain_text_queries: bool,
+    pub(crate) assume_json: bool,
+    pub(crate) request_headers: Vec<HeaderName>,
+    pub(crate) apollo_client: bool,
+    pub(crate) default_websocket_protocol: Option<WebSocketProtocols>,
+    pub(crate) status_from_errors: bool,
+    pub(crate) etag: bool,
+    pub(crate) response_content_type: &'static str,
+    pub(crate) pretty_json: bool,
+    pub(crate) json_serializer: Option<Arc<dyn JsonSerializer>>,
+    pub(crate) max_depth: Option<usize>,
+    pub(crate) max_complexity: Option<usize>,
+    pub(crate) execution_timeout: Option<Duration>,
+    pub(crate) rate_limit: Option<Arc<OperationRateLimit>>,
+    pub(crate) rejection_handler: Option<Arc<dyn RejectionHandler>>,
+    pub(crate) on_rejection: Option<RejectionCallback>,
+    pub(crate) on_response: Option<ResponseCallback>,
+    pub(crate) request_id: Option<RequestIdCallback>,
+    #[cfg(feature = "compression")]
+    pub(crate) min_compress_size: usize,
+    #[cfg(feature = "metrics")]
+    pub(crate) metrics_prefix: String,
+}
+
+impl Default for GraphQLConfig {
+    fn default() -> Self {
+        Self {
+            #[cfg(feature = "uploads")]
+            multipart: MultipartOptions::default(),
+            max_request_size: Self::DEFAULT_MAX_REQUEST_SIZE,
+            #[cfg(feature = "uploads")]
+            max_part_size: Self::DEFAULT_MAX_PART_SIZE,
+            #[cfg(feature = "uploads")]
+            max_fields: Self::DEFAULT_MAX_FIELDS,
+            #[cfg(feature = "uploads")]
+            max_uploads_size: None,
+            max_query_len: Self::DEFAULT_MAX_QUERY_LEN,
+            #[cfg(feature = "compressed_queries")]
+            compressed_query_param: None,
+            max_batch_len: Self::DEFAULT_MAX_BATCH_LEN,
+            batches: true,
+            reject_mutations_over_get: true,
+            persisted_queries_only: false,
+            #[cfg(feature = "uploads")]
+            upload_dir: None,
+            #[cfg(feature = "uploads")]
+            upload_write_buffer_size: TempFileStorage::DEFAULT_WRITE_BUFFER_SIZE,
+            #[cfg(feature = "uploads")]
+            upload_storage: None,
+            keep_raw_body: false,
+            streaming_json: false,
+            #[cfg(feature = "uploads")]
+            unmapped_files: false,
+            plain_text_queries: false,
+            assume_json: false,
+            request_headers: Vec::new(),
+            apollo_client: false,
+            default_websocket_protocol: None,
+            status_from_errors: false,
+            etag: false,
+            response_content_type: Self::DEFAULT_RESPONSE_CONTENT_TYPE,
+            pretty_json: false,
+            json_serializer: None,
+            max_depth: None,
+            max_complexity: None,
+            execution_timeout: None,
+            rate_limit: None,
+            rejection_handler: None,
+            on_rejection: None,
+            on_response: None,
+            request_id: None,
+            #[cfg(feature = "compression")]
+            min_compress_size: Self::DEFAULT_MIN_COMPRESS_SIZE,
+            #[cfg(feature = "metrics")]
+            metrics_prefix: Self::DEFAULT_METRICS_PREFIX.to_owned(),
+        }
+    }
+}
+
+impl GraphQLConfig {
+    /// Max size of the whole request body, defaults to 2 MiB.
+    pub const DEFAULT_MAX_REQUEST_SIZE: u64 = 2 * 1024 * 1024;
+
+    /// Max size of the `operations` and `map` parts of a multipart request, defaults to 256 KiB.
+    #[cfg(feature = "uploads")]
+    pub const DEFAULT_MAX_PART_SIZE: usize = 256 * 1024;
+
+    /// Max number of non-file fields of a multipart request, defaults to 16.
+    #[cfg(feature = "uploads")]
+    pub const DEFAULT_MAX_FIELDS: usize = 16;
+
+    /// Max length of the query string of a `GET` request, defaults to 16 KiB.
+    pub const DEFAULT_MAX_QUERY_LEN: usize = 16 * 1024;
+
+    /// Max number of operations in a batch request, defaults to 10.
+    pub const DEFAULT_MAX_BATCH_LEN: usize = 10;
+
+    /// Content type of JSON responses, defaults to `application/json`.
+    pub const DEFAULT_RESPONSE_CONTENT_TYPE: &'static str = "application/json";
+
+    /// Min size of a response body to be compressed, defaults to 1 KiB.
+    #[cfg(feature = "compression")]
+    pub const DEFAULT_MIN_COMPRESS_SIZE: usize = 1024;
+
+    /// Prefix of the metrics names, defaults to `graphql`.
+    #[cfg(feature = "metrics")]
+    pub const DEFAULT_METRICS_PREFIX: &'static str = "graphql";
+
+    /// Creates a default configuration.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[cfg(feature = "uploads")]
+    /// Sets the options for multipart requests.
+    #[must_use]
+    pub fn multipart(self, multipart: MultipartOptions) -> Self {
+        Self { multipart, ..self }
+    }
+
+    /// Sets the maximum size of the whole request body.
+    ///
+    /// It applies to JSON, raw query and multipart bodies alike, larger bodies are rejected with
+    /// `PayloadTooLarge`.
+    #[must_use]
+    pub fn max_request_size(self, size: u64) -> Self {
+        Self {
+            max_request_size: size,
+            ..self
+        }
+    }
+
+    #[cfg(feature = "uploads")]
+    /// Sets the maximum size of the `operations` and `map` parts of a multipart request, larger
+    /// parts are rejected with `PayloadTooLarge`.
+    #[must_use]
+    pub fn max_part_size(self, size: usize) -> Self {
+        Self {
+            max_part_size: size,
+            ..self
+        }
+    }
+
+    #[cfg(feature = "uploads")]
+    /// Sets the maximum number of non-file fields of a multipart request, including the
+    /// `operations` and `map` parts. The number of files is limited by
+    /// `MultipartOptions::max_num_files`.
+    #[must_use]
+    pub fn max_fields(self, max: usize) -> Self {
+        Self {
+            max_fields: max,
+            ..self
+        }
+    }
+
+    #[cfg(feature = "uploads")]
+    /// Sets the maximum total size of the files of a multipart request, which is only bounded by
+    /// `max_request_size` by default.
+    ///
+    /// Requests are rejected with `413 Payload Too Large` as soon as the files written exceed it.
+    #[must_use]
+    pub fn max_uploads_size(self, max: u64) -> Self {
+        Self {
+            max_uploads_size: Some(max),
+            ..self
+        }
+    }
+
+    /// Sets the maximum length of the query string of a `GET` request, longer ones are rejected
+    /// with `414 URI Too Long` before being parsed.
+    #[must_use]
+    pub fn max_query_len(self, len: usize) -> Self {
+        Self {
+            max_query_len: len,
+            ..self
+        }
+    }
+
+    #[cfg(feature = "compressed_queries")]
+    /// Reads the query of `GET` requests from the parameter `name` when it's present, as a
+    /// brotli-compressed query encoded in URL-safe base64, disabled by default.
+    ///
+    /// The decompressed query is limited to the maximum size of a request body, larger queries
+    /// are rejected with `413 Payload Too Large`.
+    #[must_use]
+    pub fn compressed_query_param(self, name: impl Into<String>) -> Self {
+        Self {
+            compressed_query_param: Some(name.into()),
+            ..self
+        }
+    }
+
+    /// Sets the maximum number of operations in a batch request.
+    #[must_use]
+    pub fn max_batch_len(self, len: usize) -> Self {
+        Self {
+            max_batch_len: len,
+            ..self
+        }
+    }
+
+    /// Accepts batch requests, enabled by default.
+    ///
+    /// Once disabled, batches are rejected with a `400` and only single requests are accepted.
+    #[must_use]
+    pub fn batches(self, enabled: bool) -> Self {
+        Self {
+            batches: enabled,
+            ..self
+        }
+    }
+
+    /// Rejects mutations and subscriptions sent over `GET`, enabled by default.
+    ///
+    /// The SSE transport, whose point is subscriptions, only rejects mutations.
+    ///
+    /// A `GET` request which only carries the hash of a persisted query is extracted with an
+    /// empty query, its operation is unknown until the schema looks the hash up. It's checked
+    /// then by the [`GraphQLLimits`](crate::GraphQLLimits) extension, without which persisted
+    /// mutations are executed over `GET`.
+    #[must_use]
+    pub fn reject_mutations_over_get(self, enabled: bool) -> Self {
+        Self {
+            reject_mutations_over_get: enabled,
+            ..self
+        }
+    }
+
+    /// Rejects requests carrying a `query`, disabled by default.
+    ///
+    /// Only requests with the hash of a persisted query, and no query, are accepted, the queries
+    /// must be registered beforehand in the cache of the `ApolloPersistedQueries` extension of
+    /// the schema, since the requests registering them are rejected too. Requests with an unknown
+    /// hash are answered by the schema with `PersistedQueryNotFound`.
+    #[must_use]
+    pub fn persisted_queries_only(self, enabled: bool) -> Self {
+        Self {
+            persisted_queries_only: enabled,
+            ..self
+        }
+    }
+
+    #[cfg(feature = "uploads")]
+    /// Sets the directory where the default storage keeps uploaded files, defaults to the OS temp
+    /// directory.
+    #[must_use]
+    pub fn upload_dir(self, dir: impl Into<PathBuf>) -> Self {
+        Self {
+            upload_dir: Some(dir.into()),
+            ..self
+        }
+    }
+
+    #[cfg(feature = "uploads")]
+    /// Sets the size of the content buffered by the default storage between writes to the temp
+    /// file of an upload, defaults to [`TempFileStorage::DEFAULT_WRITE_BUFFER_SIZE`].
+    ///
+    /// Every upload is written to a temp file, small ones are written at once.
+    #[must_use]
+    pub fn upload_write_buffer_size(self, size: usize) -> Self {
+        Self {
+            upload_write_buffer_size: size,
+            ..self
+        }
+    }
+
+    #[cfg(feature = "uploads")]
+    /// Sets the storage of uploaded files, defaults to temp files in the
+    /// [`upload_dir`](Self::upload_dir).
+    #[must_use]
+    pub fn upload_storage(self, storage: impl UploadStorage) -> Self {
+        Self {
+            upload_storage: Some(Arc::new(storage)),
+            ..self
+        }
+    }
+
+    /// Parses JSON bodies while they're received instead of buffering them first, disabled by
+    /// default.
+    ///
+    /// It lowers the peak memory of large bodies, at the cost of a blocking thread per request.
+    /// The raw body isn't available then, so it's disabled by
+    /// [`keep_raw_body`](Self::keep_raw_body), and the invalid operation of a batch isn't told.
+    #[must_use]
+    pub fn streaming_json(self, enabled: bool) -> Self {
+        Self {
+            streaming_json: enabled,
+            ..self
+        }
+    }
+
+    /// Keeps the raw request body in the `Context` extensions as
+    /// [`GraphQLRawBody`](crate::GraphQLRawBody), disabled by default.
+    #[must_use]
+    pub fn keep_raw_body(self, enabled: bool) -> Self {
+        Self {
+            keep_raw_body: enabled,
+            ..self
+        }
+    }
+
+    #[cfg(feature = "uploads")]
+    /// Keeps the uploaded files missing from the map of a multipart request, disabled by default.
+    ///
+    /// They are inserted into the data of the requests as
+    /// [`UnmappedFiles`](crate::UnmappedFiles), instead of being dropped.
+    #[must_use]
+    pub fn unmapped_files(self, enabled: bool) -> Self {
+        Self {
+            unmapped_files: enabled,
+            ..self
+        }
+    }
+
+    /// Accepts `text/plain` bodies, and bodies without `Content-Type`, as raw queries, disabled by
+    /// default.
+    ///
+    /// They are received like `application/graphql` bodies: the whole body is the query, so the
+    /// operation name and variables can't be sent.
+    #[must_use]
+    pub fn plain_text_queries(self, enabled: bool) -> Self {
+        Self {
+            plain_text_queries: enabled,
+            ..self
+        }
+    }
+
+    /// Receives bodies without `Content-Type` as JSON, disabled by default, they are rejected
+    /// with `400 Bad Request` otherwise.
+    ///
+    /// [`plain_text_queries`](Self::plain_text_queries) takes precedence.
+    #[must_use]
+    pub fn assume_json(self, enabled: bool) -> Self {
+        Self {
+            assume_json: enabled,
+            ..self
+        }
+    }
+
+    /// Sets the headers copied into the data of the requests as
+    /// [`RequestHeaders`](crate::RequestHeaders).
+    #[must_use]
+    pub fn request_headers(self, names: impl IntoIterator<Item = HeaderName>) -> Self {
+        Self {
+            request_headers: names.into_iter().collect(),
+            ..self
+        }
+    }
+
+    /// Inserts the client identification headers of Apollo clients into the data of the
+    /// requests as [`ApolloClient`](crate::ApolloClient), disabled by default.
+    #[must_use]
+    pub fn apollo_client(self, enabled: bool) -> Self {
+        Self {
+            apollo_client: enabled,
+            ..self
+        }
+    }
+
+    /// Sets the protocol selected by the [`SecWebsocketProtocol`](crate::SecWebsocketProtocol)
+    /// extractor when the upgrade request has no `Sec-WebSocket-Protocol` header, e.g. the
+    /// legacy `graphql-ws` protocol of `subscriptions-transport-ws` for older clients.
+    ///
+    /// Defaults to `None`, which rejects these requests with `400 Bad Request`.
+    #[must_use]
+    pub fn default_websocket_protocol(self, protocol: Option<WebSocketProtocols>) -> Self {
+        Self {
+            default_websocket_protocol: protocol,
+            ..self
+        }
+    }
+
+    /// Sets the max depth of operations.
+    ///
+    /// The limit is enforced by the [`GraphQLLimits`](crate::GraphQLLimits) extension, it's
+    /// silently ignored by a schema built without it, only a warning is logged once. The
+    /// `Schema::limit_depth` of the schema still applies, so the limit can only lower it: to
+    /// raise the limit of trusted callers, build the schema without a limit and set one for the
+    /// others with a middleware inserting their configuration.
+    #[must_use]
+    pub fn max_depth(self, depth: usize) -> Self {
+        Self {
+            max_depth: Some(depth),
+            ..self
+        }
+    }
+
+    /// Sets the max complexity of operations, counting the `complexity` of the fields.
+    ///
+    /// The limit is enforced by the [`GraphQLLimits`](crate::GraphQLLimits) extension, it's
+    /// silently ignored by a schema built without it, only a warning is logged once. The
+    /// `Schema::limit_complexity` of the schema still applies, so the limit can only lower it: to
+    /// raise the limit of trusted callers, build the schema without a limit and set one for the
+    /// others with a middleware inserting their configuration.
+    #[must_use]
+    pub fn max_complexity(self, complexity: usize) -> Self {
+        Self {
+            max_complexity: Some(complexity),
+            ..self
+        }
+    }
+
+    /// Sets the max time to execute a request, `None` waits forever, which is the default.
+    ///
+    /// Requests which take longer are cancelled, with a `TIMEOUT` error per operation. Applied by
+    /// the [`graphql`](crate::graphql) handler, a middleware of trusted callers can override it
+    /// by inserting another configuration, e.g. with `execution_timeout(None)`.
+    #[must_use]
+    pub fn execution_timeout(self, timeout: Option<Duration>) -> Self {
+        Self {
+            execution_timeout: timeout,
+            ..self
+        }
+    }
+
+    /// Limits the rate of operations by name, see [`OperationRateLimit`].
+    ///
+    /// The configuration shares the buckets with its clones, and is cloned into the requests:
+    /// insert the same configuration for every request, e.g. from a middleware.
+    #[must_use]
+    pub fn rate_limit(self, limit: OperationRateLimit) -> Self {
+        Self {
+            rate_limit: Some(Arc::new(limit)),
+            ..self
+        }
+    }
+
+    /// Sets the HTTP status of responses with errors from the errors, disabled by default.
+    ///
+    /// The status is taken from the `status` extension of the first error, or its `code`
+    /// extension, e.g. `UNAUTHENTICATED` is `401` and `TIMEOUT` is `504`. Otherwise request
+    /// errors are `400` and resolver errors are `500`. Applied by
+    /// [`GraphQLResponse::into_response`](crate::GraphQLResponse::into_response).
+    #[must_use]
+    pub fn status_from_errors(self, enabled: bool) -> Self {
+        Self {
+            status_from_errors: enabled,
+            ..self
+        }
+    }
+
+    /// Sets an `ETag` on responses without errors, disabled by default.
+    ///
+    /// `GET` requests with a matching `If-None-Match` header get a `304 Not Modified`.
+    /// Applied by [`GraphQLResponse::into_response`](crate::GraphQLResponse::into_response).
+    #[must_use]
+    pub fn etag(self, enabled: bool) -> Self {
+        Self {
+            etag: enabled,
+            ..self
+        }
+    }
+
+    /// Sets the content type of JSON responses, e.g. `application/graphql-response+json` of the
+    /// GraphQL over HTTP spec.
+    ///
+    /// Applied by [`GraphQLResponse::into_response`](crate::GraphQLResponse::into_response).
+    #[must_use]
+    pub fn response_content_type(self, content_type: &'static str) -> Self {
+        Self {
+            response_content_type: content_type,
+            ..self
+        }
+    }
+
+    /// Indents JSON responses, e.g. to read them with curl in development, disabled by default.
+    ///
+    /// Applied by [`GraphQLResponse::into_response`](crate::GraphQLResponse::into_response).
+    #[must_use]
+    pub fn pretty_json(self, enabled: bool) -> Self {
+        Self {
+            pretty_json: enabled,
+            ..self
+        }
+    }
+
+    /// Sets the serializer of JSON responses, which replaces `serde_json` and
+    /// [`pretty_json`](Self::pretty_json).
+    ///
+    /// Applied by [`GraphQLResponse::into_response`](crate::GraphQLResponse::into_response).
+    #[must_use]
+    pub fn json_serializer(self, serializer: impl JsonSerializer) -> Self {
+        Self {
+            json_serializer: Some(Arc::new(serializer)),
+            ..self
+        }
+    }
+
+    /// Sets the min size of a response body to be compressed.
+    ///
+    /// Bodies are compressed with `br` or `gzip` as accepted by the `Accept-Encoding` header.
+    /// Applied by [`GraphQLResponse::into_response`](crate::GraphQLResponse::into_response).
+    #[cfg(feature = "compression")]
+    #[must_use]
+    pub fn min_compress_size(self, size: usize) -> Self {
+        Self {
+            min_compress_size: size,
+            ..self
+        }
+    }
+
+    /// Sets the prefix of the metrics names, e.g. to tell routes apart.
+    ///
+    /// The extractors record `<prefix>_requests_total`, `<prefix>_rejections_total`,
+    /// `<prefix>_batch_size` and `<prefix>_upload_bytes_total`,
+    /// [`GraphQLResponse::into_response`](crate::GraphQLResponse::into_response) records
+    /// `<prefix>_errors_total`.
+    #[cfg(feature = "metrics")]
+    #[must_use]
+    pub fn metrics_prefix(self, prefix: impl Into<String>) -> Self {
+        Self {
+            metrics_prefix: prefix.into(),
+            ..self
+        }
+    }
+
+    /// Sets the conversion of rejections to responses.
+    ///
+    /// Applied by
+    /// [`GraphQLRejection::into_response`](crate::rejection::GraphQLRejection::into_response).
+    #[must_use]
+    pub fn rejection_handler(self, handler: impl RejectionHandler) -> Self {
+        Self {
+            rejection_handler: Some(Arc::new(handler)),
+            ..self
+        }
+    }
+
+    /// Specifies that a function is called with the rejections, e.g. to log them.
+    ///
+    /// The method, headers and extensions of the request are available from the `Context`, the
+    /// remote address too if the server inserts it. Called by
+    /// [`GraphQLRejection::into_response`](crate::rejection::GraphQLRejection::into_response):
+    ///
+    /// ```ignore
+    /// GraphQLConfig::new().on_rejection(|cx: &Context, rejection: &GraphQLRejection| {
+    ///     tracing::warn!(method = %cx.method(), content_type = ?cx.mime(), "{}", rejection.0)
+    /// })
+    /// ```
+    #[must_use]
+    pub fn on_rejection<F>(self, f: F) -> Self
+    where
+        F: Fn(&Context, &GraphQLRejection) + Send + Sync + 'static,
+    {
+        Self {
+            on_rejection: Some(Arc::new(f)),
+            ..self
+        }
+    }
+
+    /// Specifies that a function updates the responses, e.g. to add security headers.
+    ///
+    /// It's called last, so it can replace the headers set from the GraphQL response. Applied by
+    /// [`GraphQLResponse::into_response`](crate::GraphQLResponse::into_response).
+    #[must_use]
+    pub fn on_response<F>(self, f: F) -> Self
+    where
+        F: Fn(&mut Response) + Send + Sync + 'static,
+    {
+        Self {
+            on_response: Some(Arc::new(f)),
+            ..self
+        }
+    }
+
+    /// Specifies that a function gets the id of the requests, which is merged into the
+    /// `extensions` of the errors of their GraphQL responses as `request_id`, see
+    /// [`GraphQLResponse::with_request_id`](crate::GraphQLResponse::with_request_id).
+    ///
+    /// Applied by [`GraphQLResponse::into_response`](crate::GraphQLResponse::into_response) and
+    /// the [`graphql`](crate::graphql) handler:
+    ///
+    /// ```ignore
+    /// GraphQLConfig::new().request_id(|cx: &Context| cx.header::<String>("x-request-id"))
+    /// ```
+    #[must_use]
+    pub fn request_id<F>(self, f: F) -> Self
+    where
+        F: Fn(&Context) -> Option<String> + Send + Sync + 'static,
+    {
+        Self {
+            request_id: Some(Arc::new(f)),
+            ..self
+        }
+    }
+
+    /// Gets the configuration from the `Context` extensions, or the default one.
+    pub(crate) fn get(cx: &Context) -> Self {
+        cx.extensions().get::<Self>().cloned().unwrap_or_default()
+    }
+}
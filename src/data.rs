@@ -0,0 +1,77 @@
+use std::{any::Any, sync::Arc};
+
+use async_graphql::Data;
+
+use viz_core::Context;
+
+type Inserter = Arc<dyn Fn(&mut Data) + Send + Sync>;
+
+/// Request-scoped data inserted into the requests by the extractors.
+///
+/// Values are added to the `Context` extensions, e.g. from a middleware authenticating the user,
+/// then resolvers read them with `ctx.data::<User>()`:
+///
+/// ```ignore
+/// GraphQLData::get_mut(cx).insert(user);
+/// ```
+///
+/// Every operation of a batch request gets its own clone of each value.
+///
+/// The same values can be composed once, and reused for queries and subscriptions:
+///
+/// ```ignore
+/// let data = GraphQLData::new().with(user).with(pool.clone()).with(locale);
+/// cx.extensions_mut().insert(data.clone());
+/// GraphQLSubscription::new(websocket, schema, protocol).data(data)
+/// ```
+#[derive(Clone, Default)]
+pub struct GraphQLData(Vec<Inserter>);
+
+impl GraphQLData {
+    /// Creates an empty set of data.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gets a clone of the data of the `Context` extensions, or an empty one, e.g. to pass it
+    /// to a subscription before upgrading.
+    #[must_use]
+    pub fn get(cx: &Context) -> Self {
+        cx.extensions().get::<Self>().cloned().unwrap_or_default()
+    }
+
+    /// Gets the data of the `Context` extensions, inserting an empty one if none is present.
+    pub fn get_mut(cx: &mut Context) -> &mut Self {
+        if cx.extensions().get::<Self>().is_none() {
+            cx.extensions_mut().insert(Self::default());
+        }
+        cx.extensions_mut().get_mut::<Self>().unwrap()
+    }
+
+    /// Inserts a value, replacing the value of the same type.
+    pub fn insert<D: Any + Clone + Send + Sync>(&mut self, data: D) {
+        self.0.push(Arc::new(move |d| d.insert(data.clone())));
+    }
+
+    /// Adds a value, replacing the value of the same type.
+    #[must_use]
+    pub fn with<D: Any + Clone + Send + Sync>(mut self, data: D) -> Self {
+        self.insert(data);
+        self
+    }
+
+    /// Creates an `async_graphql::Data` of the values, e.g. to return it from the initializer
+    /// of a subscription.
+    #[must_use]
+    pub fn to_data(&self) -> Data {
+        let mut data = Data::default();
+        self.apply(&mut data);
+        data
+    }
+
+    /// Inserts the values into the data of a request.
+    pub(crate) fn apply(&self, data: &mut Data) {
+        self.0.iter().for_each(|insert| insert(data));
+    }
+}
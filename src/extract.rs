@@ -1,13 +1,27 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, io};
 
-use async_graphql::{http::MultipartOptions, ParseRequestError};
+use async_graphql::{
+    parser::{
+        parse_query,
+        types::{ExecutableDocument, OperationDefinition, OperationType},
+    },
+    ParseRequestError,
+};
 
-use viz_core::{http, types::Multipart, Context, Error, Extract, Result};
+use bytes::Bytes;
+use tokio::{sync::mpsc, task};
+use viz_core::{http, Context, Extract, Result};
+#[cfg(feature = "tracing")]
+use viz_utils::tracing;
 use viz_utils::{
     futures::{future::BoxFuture, TryStreamExt},
     serde::json,
 };
 
+#[cfg(feature = "uploads")]
+use crate::multipart::{discard_uploads, receive_multipart};
+use crate::{limits::RequestChecks, GraphQLConfig, GraphQLData};
+
 /// Extractor for GraphQL request.
 pub struct GraphQLRequest(pub async_graphql::Request);
 
@@ -19,26 +33,402 @@ impl GraphQLRequest {
     }
 }
 
+/// The raw body of a GraphQL request.
+///
+/// It's stored in the `Context` extensions by the extractors when
+/// [`GraphQLConfig::keep_raw_body`](crate::GraphQLConfig::keep_raw_body) is enabled, so an error
+/// handler can retrieve it. Multipart bodies are streamed and never kept.
+#[derive(Clone, Debug)]
+pub struct GraphQLRawBody(pub Vec<u8>);
+
+/// The uploaded files of a multipart request which are missing from the map.
+///
+/// It's inserted into the data of the requests when
+/// [`GraphQLConfig::unmapped_files`](crate::GraphQLConfig::unmapped_files) is enabled.
+#[cfg(feature = "uploads")]
+pub struct UnmappedFiles(pub Vec<async_graphql::UploadValue>);
+
+/// The headers of a GraphQL request configured with
+/// [`GraphQLConfig::request_headers`](crate::GraphQLConfig::request_headers).
+///
+/// It's inserted into the data of the requests by the extractors, headers missing from the
+/// request are absent:
+///
+/// ```ignore
+/// let request_id = ctx.data::<RequestHeaders>()?.get("x-request-id");
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct RequestHeaders(pub http::HeaderMap);
+
+impl RequestHeaders {
+    /// Gets the value of a header, if it's valid UTF-8.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).and_then(|value| value.to_str().ok())
+    }
+}
+
+/// The client identification headers of Apollo clients, enabled with
+/// [`GraphQLConfig::apollo_client`](crate::GraphQLConfig::apollo_client).
+///
+/// It's inserted into the data of the requests by the extractors, headers missing from the
+/// request, or which aren't valid UTF-8, are `None`:
+///
+/// ```ignore
+/// let client = ctx.data::<ApolloClient>()?.name.as_deref().unwrap_or("unknown");
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ApolloClient {
+    /// The `apollographql-client-name` header.
+    pub name: Option<String>,
+    /// The `apollographql-client-version` header.
+    pub version: Option<String>,
+    /// The `x-apollo-operation-name` header.
+    pub operation_name: Option<String>,
+}
+
+impl ApolloClient {
+    fn from_headers(headers: &http::HeaderMap) -> Self {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned)
+        };
+        Self {
+            name: header("apollographql-client-name"),
+            version: header("apollographql-client-version"),
+            operation_name: header("x-apollo-operation-name"),
+        }
+    }
+}
+
+/// The `traceparent` header of a GraphQL request.
+///
+/// It's inserted into the data of the requests by the extractors, so resolvers can continue the
+/// trace.
+#[cfg(feature = "tracing")]
+#[derive(Clone, Debug)]
+pub struct TraceParent(pub String);
+
 /// Rejection response types.
 pub mod rejection {
-    use async_graphql::ParseRequestError;
-    use viz_core::{http, Response};
+    use std::{collections::HashMap, error::Error as StdError, fmt, time::Duration};
+
+    use async_graphql::{parser::types::OperationType, ParseRequestError, Value};
+    use viz_core::{http, Context, Response};
+    use viz_utils::serde::json::{self, json};
+
+    use crate::GraphQLConfig;
+
+    /// Rejection used for [`GraphQLRequest`](crate::GraphQLRequest).
+    ///
+    /// Structural errors of a multipart request, like a missing `operations` or `map` part, are
+    /// rejected with `422 Unprocessable Entity`, other parse errors with `400 Bad Request`.
+    ///
+    /// Rejections of a parsed operation keep its extensions, e.g. to log the hash of a persisted
+    /// query. A persisted query which isn't found is an error of the response, not a rejection.
+    ///
+    /// It's constructed with `From`, and matched with `GraphQLRejection(err, ..)`.
+    pub struct GraphQLRejection(
+        pub ParseRequestError,
+        pub(crate) Option<HashMap<String, Value>>,
+    );
 
-    /// Rejection used for [`GraphQLRequest`](GraphQLRequest).
-    pub struct GraphQLRejection(pub ParseRequestError);
+    impl GraphQLRejection {
+        /// Gets the extensions of the rejected operation, if it was parsed.
+        #[must_use]
+        pub fn extensions(&self) -> Option<&HashMap<String, Value>> {
+            self.1.as_ref()
+        }
+
+        /// Gets the `sha256Hash` of the `persistedQuery` extension of the rejected operation.
+        #[must_use]
+        pub fn persisted_query_hash(&self) -> Option<&str> {
+            match self.extensions()?.get("persistedQuery")? {
+                Value::Object(persisted_query) => match persisted_query.get("sha256Hash")? {
+                    Value::String(hash) => Some(hash),
+                    _ => None,
+                },
+                _ => None,
+            }
+        }
+
+        /// Keeps the extensions of the rejected operation.
+        pub(crate) fn with_extensions(self, extensions: &HashMap<String, Value>) -> Self {
+            GraphQLRejection(self.0, Some(extensions.clone()))
+        }
+
+        /// Converts to a response with the [`RejectionHandler`] of the [`GraphQLConfig`] of the
+        /// `Context`, after calling its [`on_rejection`](GraphQLConfig::on_rejection) callback.
+        ///
+        /// The conversion from `GraphQLRejection` uses the default conversion.
+        pub fn into_response(self, cx: &Context) -> Response {
+            let config = GraphQLConfig::get(cx);
+            if let Some(on_rejection) = &config.on_rejection {
+                on_rejection(cx, &self);
+            }
+            match config.rejection_handler {
+                Some(handler) => handler.handle(self),
+                None => self.into(),
+            }
+        }
+    }
+
+    /// Converts rejections to responses, in place of the default conversion.
+    ///
+    /// Invalid JSON bodies are rejected with a `serde_json::Error` in
+    /// [`ParseRequestError::InvalidRequest`], which can be downcast to customize the message.
+    ///
+    /// It's implemented for closures:
+    ///
+    /// ```ignore
+    /// GraphQLConfig::new().rejection_handler(|rejection: GraphQLRejection| {
+    ///     Response::json(format!(r#"{{"message":"{}"}}"#, rejection.0))
+    /// })
+    /// ```
+    pub trait RejectionHandler: Send + Sync + 'static {
+        /// Converts the rejection to a response.
+        fn handle(&self, rejection: GraphQLRejection) -> Response;
+    }
+
+    impl<F> RejectionHandler for F
+    where
+        F: Fn(GraphQLRejection) -> Response + Send + Sync + 'static,
+    {
+        fn handle(&self, rejection: GraphQLRejection) -> Response {
+            self(rejection)
+        }
+    }
 
     impl From<GraphQLRejection> for Response {
         fn from(gr: GraphQLRejection) -> Self {
             match gr.0 {
-                ParseRequestError::PayloadTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE.into(),
-                bad_request => (http::StatusCode::BAD_REQUEST, format!("{:?}", bad_request)).into(),
+                ParseRequestError::InvalidRequest(err) => match err.downcast::<ExtractError>() {
+                    Ok(err) => (*err).into(),
+                    Err(err) => match err.downcast::<json::Error>() {
+                        Ok(err) => json_error_response(*err),
+                        Err(err) => {
+                            let err = ParseRequestError::InvalidRequest(err);
+                            error_response(http::StatusCode::BAD_REQUEST, &err, &err)
+                        }
+                    },
+                },
+                err @ ParseRequestError::PayloadTooLarge => {
+                    error_response(http::StatusCode::PAYLOAD_TOO_LARGE, &err, &err)
+                }
+                err @ (ParseRequestError::InvalidFilesMap(_)
+                | ParseRequestError::MissingOperatorsPart
+                | ParseRequestError::MissingMapPart
+                | ParseRequestError::NotUpload
+                | ParseRequestError::MissingFiles) => error_response(
+                    http::StatusCode::UNPROCESSABLE_ENTITY,
+                    &multipart_message(&err),
+                    &err,
+                ),
+                err => error_response(http::StatusCode::BAD_REQUEST, &err, &err),
             }
         }
     }
 
+    /// The message of a structural error of a multipart request, the messages of
+    /// `ParseRequestError` don't name the parts of the spec.
+    fn multipart_message(err: &ParseRequestError) -> String {
+        match err {
+            ParseRequestError::InvalidFilesMap(err) => {
+                format!("invalid `map` part of multipart request: {}", err)
+            }
+            ParseRequestError::MissingOperatorsPart => {
+                "missing `operations` part of multipart request".to_owned()
+            }
+            ParseRequestError::MissingMapPart => {
+                "missing `map` part of multipart request".to_owned()
+            }
+            ParseRequestError::NotUpload => {
+                "multipart request is not an upload operation".to_owned()
+            }
+            ParseRequestError::MissingFiles => {
+                "files of the `map` are missing from multipart request".to_owned()
+            }
+            err => err.to_string(),
+        }
+    }
+
+    /// Creates a response with a GraphQL error body, the detail goes into the extensions.
+    fn error_response(
+        status: http::StatusCode,
+        message: &dyn fmt::Display,
+        detail: &dyn fmt::Debug,
+    ) -> Response {
+        Response::json(error_body(message, detail).to_string()).with_status(status)
+    }
+
+    /// Creates a GraphQL error body, the detail goes into the extensions.
+    fn error_body(message: &dyn fmt::Display, detail: &dyn fmt::Debug) -> json::Value {
+        json!({
+            "errors": [{
+                "message": message.to_string(),
+                "extensions": { "detail": format!("{:?}", detail) },
+            }],
+        })
+    }
+
+    /// Creates a response for an invalid JSON body, with the location of the error in the
+    /// extensions.
+    fn json_error_response(err: json::Error) -> Response {
+        let (line, column) = (err.line(), err.column());
+        let err = ParseRequestError::InvalidRequest(Box::new(err));
+        let mut body = error_body(&err, &err);
+        if line > 0 {
+            body["errors"][0]["extensions"]["line"] = line.into();
+            body["errors"][0]["extensions"]["column"] = column.into();
+        }
+        Response::json(body.to_string()).with_status(http::StatusCode::BAD_REQUEST)
+    }
+
     impl From<ParseRequestError> for GraphQLRejection {
         fn from(err: ParseRequestError) -> Self {
-            GraphQLRejection(err)
+            GraphQLRejection(err, None)
+        }
+    }
+
+    /// Errors raised by the extractors on top of [`ParseRequestError`].
+    ///
+    /// They are carried by [`ParseRequestError::InvalidRequest`].
+    #[derive(Debug)]
+    pub enum ExtractError {
+        /// The batch request contains more operations than allowed.
+        TooManyOperations {
+            /// The number of operations in the batch.
+            count: usize,
+            /// The maximum number of operations.
+            max: usize,
+        },
+        /// The operation must not be sent over `GET`.
+        NotAllowedOverGet(OperationType),
+        /// The query string of the `GET` request is longer than allowed.
+        QueryTooLong {
+            /// The length of the query string.
+            len: usize,
+            /// The maximum length of the query string.
+            max: usize,
+        },
+        /// The request carries a query, only persisted queries are allowed.
+        QueryNotAllowed,
+        /// The `POST` request has no `Content-Type`.
+        MissingContentType,
+        /// The `POST` request has an empty body.
+        EmptyBody,
+        /// Multipart requests are received with the `uploads` feature.
+        MultipartNotSupported,
+        /// The method is neither `GET` nor `POST`.
+        MethodNotAllowed(http::Method),
+        /// Batch requests are disabled.
+        BatchNotAllowed,
+        /// The operation is over its rate limit.
+        RateLimited {
+            /// The name of the operation, `None` for the default bucket.
+            operation: Option<String>,
+            /// The time until the operation is allowed again.
+            retry_after: Duration,
+        },
+        /// An operation of a JSON batch request is invalid.
+        InvalidOperation {
+            /// The index of the first invalid operation in the batch.
+            index: usize,
+            /// The error of the operation.
+            error: json::Error,
+        },
+    }
+
+    impl fmt::Display for ExtractError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                ExtractError::TooManyOperations { count, max } => write!(
+                    f,
+                    "too many operations in batch request: {} (max {})",
+                    count, max
+                ),
+                ExtractError::NotAllowedOverGet(ty) => {
+                    write!(f, "{} operations are not allowed over GET", ty)
+                }
+                ExtractError::QueryTooLong { len, max } => {
+                    write!(f, "query string is too long: {} (max {})", len, max)
+                }
+                ExtractError::QueryNotAllowed => f.write_str("only persisted queries are allowed"),
+                ExtractError::MissingContentType => f.write_str("missing Content-Type header"),
+                ExtractError::EmptyBody => f.write_str("empty request body"),
+                ExtractError::MultipartNotSupported => {
+                    f.write_str("multipart requests are not supported")
+                }
+                ExtractError::MethodNotAllowed(method) => {
+                    write!(f, "{} requests are not allowed", method)
+                }
+                ExtractError::BatchNotAllowed => f.write_str("batch requests are not allowed"),
+                ExtractError::RateLimited {
+                    operation: Some(operation),
+                    ..
+                } => write!(f, "rate limit exceeded for operation `{}`", operation),
+                ExtractError::RateLimited {
+                    operation: None, ..
+                } => f.write_str("rate limit exceeded"),
+                ExtractError::InvalidOperation { index, error } => {
+                    write!(f, "invalid operation {} in batch request: {}", index, error)
+                }
+            }
+        }
+    }
+
+    impl StdError for ExtractError {}
+
+    impl From<ExtractError> for Response {
+        fn from(err: ExtractError) -> Self {
+            match err {
+                ExtractError::TooManyOperations { .. }
+                | ExtractError::BatchNotAllowed
+                | ExtractError::QueryNotAllowed
+                | ExtractError::MissingContentType
+                | ExtractError::EmptyBody => {
+                    error_response(http::StatusCode::BAD_REQUEST, &err, &err)
+                }
+                ExtractError::NotAllowedOverGet(_) => {
+                    let mut resp = error_response(http::StatusCode::METHOD_NOT_ALLOWED, &err, &err);
+                    resp.headers_mut()
+                        .insert(http::header::ALLOW, http::HeaderValue::from_static("POST"));
+                    resp
+                }
+                ExtractError::QueryTooLong { .. } => {
+                    error_response(http::StatusCode::URI_TOO_LONG, &err, &err)
+                }
+                ExtractError::RateLimited { retry_after, .. } => {
+                    let mut resp = error_response(http::StatusCode::TOO_MANY_REQUESTS, &err, &err);
+                    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                    resp.headers_mut()
+                        .insert(http::header::RETRY_AFTER, http::HeaderValue::from(secs));
+                    resp
+                }
+                ExtractError::MultipartNotSupported => {
+                    error_response(http::StatusCode::UNSUPPORTED_MEDIA_TYPE, &err, &err)
+                }
+                ExtractError::MethodNotAllowed(_) => {
+                    let mut resp = error_response(http::StatusCode::METHOD_NOT_ALLOWED, &err, &err);
+                    resp.headers_mut().insert(
+                        http::header::ALLOW,
+                        http::HeaderValue::from_static("GET, POST"),
+                    );
+                    resp
+                }
+                ExtractError::InvalidOperation { index, .. } => {
+                    let mut body = error_body(&err, &err);
+                    body["errors"][0]["extensions"]["index"] = index.into();
+                    Response::json(body.to_string()).with_status(http::StatusCode::BAD_REQUEST)
+                }
+            }
+        }
+    }
+
+    impl From<ExtractError> for GraphQLRejection {
+        fn from(err: ExtractError) -> Self {
+            GraphQLRejection::from(ParseRequestError::InvalidRequest(Box::new(err)))
         }
     }
 }
@@ -55,8 +445,54 @@ impl Extract for GraphQLRequest {
     }
 }
 
+/// Extractor for GraphQL request, along with the headers of the request.
+///
+/// It lets a handler compute the data of the request from its headers:
+///
+/// ```ignore
+/// async fn handler(GraphQLRequestWithHeaders(request, headers): GraphQLRequestWithHeaders) -> ... {
+///     let token = headers.get(AUTHORIZATION).and_then(|value| value.to_str().ok());
+///     schema.execute(request.data(Token::from(token))).await
+/// }
+/// ```
+pub struct GraphQLRequestWithHeaders(pub async_graphql::Request, pub http::HeaderMap);
+
+impl GraphQLRequestWithHeaders {
+    /// Unwraps the value to `async_graphql::Request` and the headers.
+    #[must_use]
+    pub fn into_inner(self) -> (async_graphql::Request, http::HeaderMap) {
+        (self.0, self.1)
+    }
+}
+
+impl Extract for GraphQLRequestWithHeaders {
+    type Error = rejection::GraphQLRejection;
+
+    fn extract(cx: &mut Context) -> BoxFuture<'_, Result<Self, Self::Error>> {
+        Box::pin(async move {
+            let headers = cx.headers().clone();
+            Ok(GraphQLRequestWithHeaders(
+                GraphQLRequest::extract(cx).await?.0,
+                headers,
+            ))
+        })
+    }
+}
+
 /// Extractor for GraphQL batch request.
-pub struct GraphQLBatchRequest(pub async_graphql::BatchRequest);
+///
+/// Automatic persisted queries are resolved by the schema: enable the `apollo_persisted_queries`
+/// feature and register the [`persisted_queries`](crate::persisted_queries) extension on the
+/// schema. Requests that only carry the `persistedQuery` extension, including `GET`
+/// requests without a `query` parameter, are extracted with an empty query, so the schema can
+/// look the hash up or answer with `PersistedQueryNotFound`. The operation type of such `GET`
+/// requests is checked by the [`GraphQLLimits`](crate::GraphQLLimits) extension.
+///
+/// It's only constructed by its extraction, and matched with `GraphQLBatchRequest(batch, ..)`.
+pub struct GraphQLBatchRequest(
+    pub async_graphql::BatchRequest,
+    pub(crate) Vec<OperationType>,
+);
 
 impl GraphQLBatchRequest {
     /// Unwraps the value to `async_graphql::BatchRequest`.
@@ -64,135 +500,848 @@ impl GraphQLBatchRequest {
     pub fn into_inner(self) -> async_graphql::BatchRequest {
         self.0
     }
+
+    /// Gets the types of the operations to be executed, each type once, from the queries parsed
+    /// by the extraction.
+    ///
+    /// Queries which can't be parsed, e.g. the empty queries of persisted queries, have no type.
+    ///
+    /// ```ignore
+    /// if request.operation_types().contains(&OperationType::Mutation) {
+    ///     ...
+    /// }
+    /// ```
+    #[must_use]
+    pub fn operation_types(&self) -> &[OperationType] {
+        &self.1
+    }
+
+    /// Tells a single request from a batch, unlike [`GraphQLRequest`] which rejects batches.
+    ///
+    /// ```ignore
+    /// match request.into_kind() {
+    ///     GraphQLRequestKind::Single(request) => ...,
+    ///     GraphQLRequestKind::Batch(requests) => ...,
+    /// }
+    /// ```
+    #[must_use]
+    pub fn into_kind(self) -> GraphQLRequestKind {
+        match self.0 {
+            async_graphql::BatchRequest::Single(request) => {
+                GraphQLRequestKind::Single(GraphQLRequest(request))
+            }
+            async_graphql::BatchRequest::Batch(requests) => GraphQLRequestKind::Batch(requests),
+        }
+    }
+}
+
+/// A single request or a batch, from [`GraphQLBatchRequest::into_kind`].
+pub enum GraphQLRequestKind {
+    /// A single request.
+    Single(GraphQLRequest),
+    /// The requests of a batch.
+    Batch(Vec<async_graphql::Request>),
 }
 
 impl Extract for GraphQLBatchRequest {
     type Error = rejection::GraphQLRejection;
 
     fn extract(cx: &mut Context) -> BoxFuture<'_, Result<Self, Self::Error>> {
-        Box::pin(async move {
-            if http::Method::GET == cx.method() {
-                Ok(Self(async_graphql::BatchRequest::Single(
-                    cx.query()
-                        .map_err(|e| ParseRequestError::InvalidRequest(Box::from(e)))?,
-                )))
+        Self::extract_allowing_over_get(cx, &[OperationType::Query])
+    }
+}
+
+impl GraphQLBatchRequest {
+    /// Extracts the batch like [`Extract::extract`], with the types of the operations allowed
+    /// over `GET` when [`GraphQLConfig::reject_mutations_over_get`] is enabled.
+    pub(crate) fn extract_allowing_over_get<'a>(
+        cx: &'a mut Context,
+        allowed_over_get: &'static [OperationType],
+    ) -> BoxFuture<'a, Result<Self, rejection::GraphQLRejection>> {
+        #[cfg(feature = "metrics")]
+        let metrics_config = GraphQLConfig::get(cx);
+
+        let extract = async move {
+            let config = GraphQLConfig::get(cx);
+
+            if http::Method::GET != cx.method() && http::Method::POST != cx.method() {
+                return Err(rejection::ExtractError::MethodNotAllowed(cx.method().clone()).into());
+            }
+
+            // Bodies announced larger than allowed, or empty, are rejected before reading.
+            let content_length = cx
+                .headers()
+                .get(http::header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+            if content_length.map_or(false, |len| len > config.max_request_size) {
+                return Err(ParseRequestError::PayloadTooLarge.into());
+            }
+            if http::Method::POST == cx.method() && content_length == Some(0) {
+                return Err(rejection::ExtractError::EmptyBody.into());
+            }
+
+            let mut uploads = Vec::new();
+            let mut batch = if http::Method::GET == cx.method() {
+                async_graphql::BatchRequest::Single(receive_query(cx, &config)?)
+            } else if cx.mime().map_or(false, |m| is_graphql(&m))
+                || (config.plain_text_queries && cx.mime().map_or(true, |m| is_plain_text(&m)))
+            {
+                async_graphql::BatchRequest::Single(receive_graphql(cx, &config).await?)
+            } else if let Ok(multipart) = cx.multipart() {
+                let (batch, files) = receive_multipart(multipart, &config).await?;
+                uploads = files;
+                batch
             } else {
-                if let Ok(multipart) = cx.multipart() {
-                    if let Ok(mut state) = multipart.state().lock() {
-                        let opts = MultipartOptions::default();
-                        let mut limits = state.limits_mut();
-                        limits.file_size = opts.max_file_size;
-                        limits.files = opts.max_num_files;
-                    }
+                receive_json(cx, &config).await?
+            };
 
-                    Ok(Self(receive_batch_multipart(multipart).await.map_err(
-                        |e| ParseRequestError::InvalidRequest(Box::from(e)),
-                    )?))
-                } else {
-                    Ok(Self(cx.json().await.map_err(|e| {
-                        ParseRequestError::InvalidRequest(Box::from(e))
-                    })?))
+            // The stored uploads are discarded if the received batch is rejected.
+            let operation_types = match check_batch(
+                &batch,
+                &config,
+                http::Method::GET == cx.method(),
+                allowed_over_get,
+            ) {
+                Ok(operation_types) => operation_types,
+                Err(rejection) => {
+                    discard_uploads(&config, uploads).await;
+                    return Err(rejection);
                 }
+            };
+
+            // The checks which need the schema are enforced by its `GraphQLLimits` extension, the
+            // operation of a persisted query is only known once the schema looked the hash up.
+            let over_get = http::Method::GET == cx.method() && config.reject_mutations_over_get;
+            for_each_request(&mut batch, |request| {
+                let checks = RequestChecks::new(
+                    config.max_depth,
+                    config.max_complexity,
+                    (over_get && request.query.is_empty()).then(|| allowed_over_get),
+                    request
+                        .query
+                        .is_empty()
+                        .then(|| config.rate_limit.clone())
+                        .flatten(),
+                );
+                if let Some(checks) = checks {
+                    request.data.insert(checks);
+                }
+            });
+
+            if let Some(data) = cx.extensions().get::<GraphQLData>() {
+                for_each_request(&mut batch, |request| data.apply(&mut request.data));
             }
-        })
-    }
-}
 
-async fn receive_batch_multipart(mut multipart: Multipart) -> Result<async_graphql::BatchRequest> {
-    let mut request = None;
-    let mut map = None;
-    let mut files = Vec::new();
-
-    while let Some(mut field) = multipart.try_next().await? {
-        // in multipart, each field / file can actually have a own Content-Type.
-        // We use this to determine the encoding of the graphql query
-        let content_type = field
-            .content_type
-            .to_owned()
-            .unwrap_or(mime::APPLICATION_JSON);
-
-        let name = field.name.clone();
-
-        match name.as_str() {
-            "operations" => {
-                let body = field.bytes().await?;
-                request = Some(json::from_slice(&body)?)
-            }
-            "map" => {
-                let map_bytes = field.bytes().await?;
-
-                match (content_type.type_(), content_type.subtype()) {
-                    // cbor is in application/octet-stream.
-                    // TODO: wait for mime to add application/cbor and match against that too
-                    // Note: we actually differ here from the inoffical spec for this:
-                    // (https://github.com/jaydenseric/graphql-multipart-request-spec#multipart-form-field-structure)
-                    // It says: "map: A JSON encoded map of where files occurred in the operations.
-                    // For each file, the key is the file multipart form field name and the value is an array of operations paths."
-                    // However, I think, that since we accept CBOR as operation, which is valid, we should also accept it
-                    // as the mapping for the files.
-                    #[cfg(feature = "cbor")]
-                    (mime::OCTET_STREAM, _) | (mime::APPLICATION, mime::OCTET_STREAM) => {
-                        map = Some(
-                            serde_cbor::from_slice::<HashMap<String, Vec<String>>>(&map_bytes)
-                                .map_err(|e| ParseRequestError::InvalidFilesMap(Box::new(e)))?,
-                        );
+            if !config.request_headers.is_empty() {
+                let mut headers = RequestHeaders::default();
+                for name in &config.request_headers {
+                    for value in cx.headers().get_all(name) {
+                        headers.0.append(name.clone(), value.clone());
                     }
-                    // default to json
-                    _ => {
-                        map = Some(
-                            json::from_slice::<HashMap<String, Vec<String>>>(&map_bytes)
-                                .map_err(|e| ParseRequestError::InvalidFilesMap(Box::new(e)))?,
-                        );
+                }
+                for_each_request(&mut batch, |request| request.data.insert(headers.clone()));
+            }
+
+            if config.apollo_client {
+                let client = ApolloClient::from_headers(cx.headers());
+                #[cfg(feature = "tracing")]
+                {
+                    let span = tracing::Span::current();
+                    if let Some(name) = &client.name {
+                        span.record("client_name", name.as_str());
+                    }
+                    if let Some(version) = &client.version {
+                        span.record("client_version", version.as_str());
                     }
                 }
+                for_each_request(&mut batch, |request| request.data.insert(client.clone()));
             }
-            _ => {
-                if !name.is_empty() {
-                    if let Some(filename) = field.filename.to_owned() {
-                        let mut file = tempfile::tempfile().map_err(ParseRequestError::Io)?;
-                        field.copy_to_file(&mut file).await?;
-                        files.push((name, filename, Some(content_type.to_string()), file));
+
+            #[cfg(feature = "tracing")]
+            {
+                let span = tracing::Span::current();
+                match &batch {
+                    async_graphql::BatchRequest::Single(request) => {
+                        span.record("batch", false);
+                        if let Some(operation_name) = &request.operation_name {
+                            span.record("operation_name", operation_name.as_str());
+                        }
                     }
+                    async_graphql::BatchRequest::Batch(requests) => {
+                        span.record("batch", true);
+                        span.record("operations", requests.len());
+                    }
+                }
+
+                if let Some(traceparent) = cx.header::<String>("traceparent") {
+                    for_each_request(&mut batch, |request| {
+                        request.data.insert(TraceParent(traceparent.clone()))
+                    });
                 }
             }
+
+            Ok(Self(batch, operation_types))
+        };
+
+        #[cfg(feature = "metrics")]
+        let extract = async move {
+            let result = extract.await;
+            match &result {
+                Ok(batch) => crate::metrics::request(&metrics_config, &batch.0),
+                Err(_) => crate::metrics::rejection(&metrics_config),
+            }
+            result
+        };
+
+        #[cfg(feature = "tracing")]
+        let extract = tracing::Instrument::instrument(
+            extract,
+            tracing::info_span!(
+                "graphql.extract",
+                batch = tracing::field::Empty,
+                operations = tracing::field::Empty,
+                operation_name = tracing::field::Empty,
+                body_size = tracing::field::Empty,
+                files = tracing::field::Empty,
+                client_name = tracing::field::Empty,
+                client_version = tracing::field::Empty,
+            ),
+        );
+
+        Box::pin(extract)
+    }
+}
+
+/// Checks the received batch against the configuration, parsing every query once, and gets the
+/// types of its operations.
+fn check_batch(
+    batch: &async_graphql::BatchRequest,
+    config: &GraphQLConfig,
+    is_get: bool,
+    allowed_over_get: &[OperationType],
+) -> Result<Vec<OperationType>, rejection::GraphQLRejection> {
+    if let async_graphql::BatchRequest::Batch(requests) = batch {
+        if !config.batches {
+            return Err(rejection::ExtractError::BatchNotAllowed.into());
+        }
+        if requests.len() > config.max_batch_len {
+            return Err(rejection::ExtractError::TooManyOperations {
+                count: requests.len(),
+                max: config.max_batch_len,
+            }
+            .into());
         }
     }
 
-    let mut request = request.ok_or(ParseRequestError::MissingOperatorsPart)?;
-    let map = map.as_mut().ok_or(ParseRequestError::MissingMapPart)?;
+    let requests = match batch {
+        async_graphql::BatchRequest::Single(request) => std::slice::from_ref(request),
+        async_graphql::BatchRequest::Batch(requests) => requests.as_slice(),
+    };
+    // Every query is parsed once, queries which can't be parsed are left to the schema.
+    let mut operation_types = Vec::new();
+    for request in requests {
+        if config.persisted_queries_only && !request.query.is_empty() {
+            return Err(rejection::GraphQLRejection::from(
+                rejection::ExtractError::QueryNotAllowed,
+            )
+            .with_extensions(&request.extensions));
+        }
 
-    for (name, filename, content_type, content) in files {
-        if let Some(var_paths) = map.remove(&name) {
-            let upload = async_graphql::UploadValue {
-                filename,
-                content_type,
-                content,
-            };
+        let document = parse_query(&request.query).ok();
+        let operation = document
+            .as_ref()
+            .and_then(|document| find_operation(document, request.operation_name.as_deref()));
 
-            for var_path in var_paths {
-                match &mut request {
-                    async_graphql::BatchRequest::Single(request) => {
-                        request.set_upload(&var_path, upload.try_clone()?);
-                    }
-                    async_graphql::BatchRequest::Batch(requests) => {
-                        let mut s = var_path.splitn(2, '.');
-                        let idx = s.next().and_then(|idx| idx.parse::<usize>().ok());
-                        let path = s.next();
-
-                        if let (Some(idx), Some(path)) = (idx, path) {
-                            if let Some(request) = requests.get_mut(idx) {
-                                request.set_upload(path, upload.try_clone()?);
-                            }
-                        }
-                    }
+        if let Some(operation) = operation {
+            if config.reject_mutations_over_get
+                && is_get
+                && !allowed_over_get.contains(&operation.ty)
+            {
+                return Err(rejection::GraphQLRejection::from(
+                    rejection::ExtractError::NotAllowedOverGet(operation.ty),
+                )
+                .with_extensions(&request.extensions));
+            }
+            if !operation_types.contains(&operation.ty) {
+                operation_types.push(operation.ty);
+            }
+        }
+
+        // The rate limit of a persisted query is charged once the schema looked the hash up.
+        let rate_limit = config
+            .rate_limit
+            .as_ref()
+            .filter(|_| !request.query.is_empty());
+        if let Some(rate_limit) = rate_limit {
+            let operation_name = operation_name(request, document.as_ref());
+            if let Err(retry_after) = rate_limit.check(operation_name.as_deref()) {
+                return Err(rejection::ExtractError::RateLimited {
+                    operation: operation_name,
+                    retry_after,
                 }
+                .into());
             }
         }
     }
 
-    if !map.is_empty() {
-        return Err(Error::from(async_graphql::ParseRequestError::MissingFiles));
+    Ok(operation_types)
+}
+
+fn for_each_request(
+    batch: &mut async_graphql::BatchRequest,
+    f: impl FnMut(&mut async_graphql::Request),
+) {
+    match batch {
+        async_graphql::BatchRequest::Single(request) => std::iter::once(request).for_each(f),
+        async_graphql::BatchRequest::Batch(requests) => requests.iter_mut().for_each(f),
+    }
+}
+
+/// Receives a request from the query string of a `GET` request.
+///
+/// `variables` and `extensions` are sent as JSON-encoded strings. The `query` is optional, e.g.
+/// Apollo and Relay clients only send the `persistedQuery` extension once the query is known by
+/// the server, it's left empty for the schema to look the hash up.
+///
+/// Query strings longer than `max_query_len` are rejected before being parsed.
+pub(crate) fn receive_query(
+    cx: &Context,
+    config: &GraphQLConfig,
+) -> Result<async_graphql::Request, ParseRequestError> {
+    let len = cx.uri().query().map_or(0, str::len);
+    if len > config.max_query_len {
+        return Err(ParseRequestError::InvalidRequest(Box::new(
+            rejection::ExtractError::QueryTooLong {
+                len,
+                max: config.max_query_len,
+            },
+        )));
+    }
+
+    let mut params = cx
+        .query::<HashMap<String, String>>()
+        .map_err(|e| ParseRequestError::InvalidRequest(Box::new(e)))?;
+
+    let query = params.remove("query").unwrap_or_default();
+
+    // The compressed query takes precedence over the `query` parameter.
+    #[cfg(feature = "compressed_queries")]
+    let query = match config
+        .compressed_query_param
+        .as_ref()
+        .and_then(|name| Some((name, params.remove(name)?)))
+    {
+        Some((name, compressed)) => decompress_query(name, &compressed, config.max_request_size)?,
+        None => query,
+    };
+
+    let mut request = async_graphql::Request::new(query);
+
+    if let Some(operation_name) = params.remove("operationName") {
+        request = request.operation_name(operation_name);
+    }
+
+    if let Some(variables) = params.remove("variables").filter(|v| !v.is_empty()) {
+        request.variables = json::from_str(&variables).map_err(|e| {
+            ParseRequestError::InvalidRequest(Box::from(format!(
+                "invalid `variables` parameter: {}",
+                e
+            )))
+        })?;
+    }
+
+    if let Some(extensions) = params.remove("extensions").filter(|v| !v.is_empty()) {
+        request.extensions = json::from_str(&extensions).map_err(|e| {
+            ParseRequestError::InvalidRequest(Box::from(format!(
+                "invalid `extensions` parameter: {}",
+                e
+            )))
+        })?;
     }
 
     Ok(request)
 }
+
+/// Decodes a brotli-compressed query in URL-safe base64, failing as soon as it's larger than
+/// `max_size` once decompressed.
+#[cfg(feature = "compressed_queries")]
+fn decompress_query(
+    name: &str,
+    compressed: &str,
+    max_size: u64,
+) -> Result<String, ParseRequestError> {
+    use std::io::Read;
+
+    let invalid = |e: &dyn std::fmt::Display| {
+        ParseRequestError::InvalidRequest(Box::from(format!("invalid `{}` parameter: {}", name, e)))
+    };
+
+    let data = base64::decode_config(compressed, base64::URL_SAFE).map_err(|e| invalid(&e))?;
+    let mut query = Vec::new();
+    brotli::Decompressor::new(data.as_slice(), 4096)
+        .take(max_size + 1)
+        .read_to_end(&mut query)
+        .map_err(|e| invalid(&e))?;
+    if query.len() as u64 > max_size {
+        return Err(ParseRequestError::PayloadTooLarge);
+    }
+
+    String::from_utf8(query).map_err(|e| invalid(&e))
+}
+
+/// Gets the name of the operation to be executed: the `operationName` of the request, or the
+/// name of the only operation of the query.
+pub(crate) fn operation_name(
+    request: &async_graphql::Request,
+    document: Option<&ExecutableDocument>,
+) -> Option<String> {
+    if let Some(operation_name) = &request.operation_name {
+        return Some(operation_name.clone());
+    }
+
+    let mut operations = document?.operations.iter();
+    match (operations.next(), operations.next()) {
+        (Some((name, _)), None) => name.map(|name| name.to_string()),
+        _ => None,
+    }
+}
+
+/// Finds the operation to be executed in the document.
+pub(crate) fn find_operation<'a>(
+    document: &'a ExecutableDocument,
+    operation_name: Option<&str>,
+) -> Option<&'a OperationDefinition> {
+    let mut operations = document.operations.iter();
+
+    match operation_name {
+        Some(operation_name) => operations
+            .find(|(name, _)| name.map_or(false, |name| name.as_str() == operation_name))
+            .map(|(_, operation)| &operation.node),
+        None => operations.next().map(|(_, operation)| &operation.node),
+    }
+}
+
+/// Matches `application/graphql`, but not `application/graphql+json`, which is JSON.
+fn is_graphql(m: &mime::Mime) -> bool {
+    m.type_() == mime::APPLICATION && m.subtype() == "graphql" && m.suffix().is_none()
+}
+
+fn is_plain_text(m: &mime::Mime) -> bool {
+    m.type_() == mime::TEXT && m.subtype() == mime::PLAIN
+}
+
+/// Matches `application/json` and the `+json` structured suffix, whatever the parameters.
+fn is_json(m: &mime::Mime) -> bool {
+    m.type_() == mime::APPLICATION && (m.subtype() == mime::JSON || m.suffix() == Some(mime::JSON))
+}
+
+/// Reads the whole request body, bounded by the configured `max_request_size`.
+///
+/// The body is kept as [`GraphQLRawBody`] if enabled.
+async fn receive_body(
+    cx: &mut Context,
+    config: &GraphQLConfig,
+) -> Result<Vec<u8>, ParseRequestError> {
+    let limit = config.max_request_size;
+    let mut body = cx
+        .take_body()
+        .ok_or_else(|| ParseRequestError::InvalidRequest(Box::from("missing request body")))?;
+    let mut data = Vec::new();
+
+    while let Some(chunk) = body
+        .try_next()
+        .await
+        .map_err(|e| ParseRequestError::InvalidRequest(Box::new(e)))?
+    {
+        if (data.len() + chunk.len()) as u64 > limit {
+            return Err(ParseRequestError::PayloadTooLarge);
+        }
+        data.extend_from_slice(&chunk);
+    }
+
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record("body_size", data.len());
+
+    if config.keep_raw_body {
+        cx.extensions_mut().insert(GraphQLRawBody(data.clone()));
+    }
+
+    Ok(data)
+}
+
+/// Receives a JSON request body.
+async fn receive_json(
+    cx: &mut Context,
+    config: &GraphQLConfig,
+) -> Result<async_graphql::BatchRequest, ParseRequestError> {
+    if !cx.headers().contains_key(http::header::CONTENT_TYPE) {
+        if !config.assume_json {
+            return Err(ParseRequestError::InvalidRequest(Box::new(
+                rejection::ExtractError::MissingContentType,
+            )));
+        }
+    } else if !cx.mime().map_or(false, |m| is_json(&m)) {
+        return Err(ParseRequestError::InvalidRequest(Box::from(
+            "unsupported media type",
+        )));
+    }
+
+    if config.streaming_json && !config.keep_raw_body {
+        return receive_json_stream(cx, config).await;
+    }
+
+    let body = receive_body(cx, config).await?;
+    if body.is_empty() {
+        return Err(ParseRequestError::InvalidRequest(Box::new(
+            rejection::ExtractError::EmptyBody,
+        )));
+    }
+    json::from_slice(&body).map_err(|e| match invalid_operation(&body) {
+        Some(err) => ParseRequestError::InvalidRequest(Box::new(err)),
+        None => ParseRequestError::InvalidRequest(Box::new(e)),
+    })
+}
+
+/// Parses a JSON request body while it's received, bounded by the configured
+/// `max_request_size`.
+///
+/// `serde_json` only reads synchronously, so the body is parsed on a blocking thread, which
+/// receives the chunks one by one.
+async fn receive_json_stream(
+    cx: &mut Context,
+    config: &GraphQLConfig,
+) -> Result<async_graphql::BatchRequest, ParseRequestError> {
+    let limit = config.max_request_size;
+    let mut body = cx
+        .take_body()
+        .ok_or_else(|| ParseRequestError::InvalidRequest(Box::from("missing request body")))?;
+
+    let (tx, rx) = mpsc::channel(1);
+    let parser = task::spawn_blocking(move || {
+        json::from_reader::<_, async_graphql::BatchRequest>(ChunksReader {
+            chunks: rx,
+            chunk: Bytes::new(),
+        })
+    });
+
+    // The parser stops at the end of the body, dropping the sender ends it early.
+    let mut size = 0;
+    while let Some(chunk) = body
+        .try_next()
+        .await
+        .map_err(|e| ParseRequestError::InvalidRequest(Box::new(e)))?
+    {
+        size += chunk.len() as u64;
+        if size > limit {
+            return Err(ParseRequestError::PayloadTooLarge);
+        }
+        if tx.send(chunk).await.is_err() {
+            break;
+        }
+    }
+    drop(tx);
+
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record("body_size", size);
+
+    if size == 0 {
+        return Err(ParseRequestError::InvalidRequest(Box::new(
+            rejection::ExtractError::EmptyBody,
+        )));
+    }
+
+    parser
+        .await
+        .map_err(|e| ParseRequestError::InvalidRequest(Box::new(e)))?
+        .map_err(|e| ParseRequestError::InvalidRequest(Box::new(e)))
+}
+
+/// A blocking reader of the chunks of a body.
+struct ChunksReader {
+    chunks: mpsc::Receiver<Bytes>,
+    chunk: Bytes,
+}
+
+impl io::Read for ChunksReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.chunks.blocking_recv() {
+                Some(chunk) => self.chunk = chunk,
+                None => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.len());
+        buf[..len].copy_from_slice(&self.chunk.split_to(len));
+        Ok(len)
+    }
+}
+
+/// Finds the first invalid operation of a batch body, to tell which one broke it.
+fn invalid_operation(body: &[u8]) -> Option<rejection::ExtractError> {
+    json::from_slice::<Vec<json::Value>>(body)
+        .ok()?
+        .into_iter()
+        .enumerate()
+        .find_map(|(index, operation)| {
+            json::from_value::<async_graphql::Request>(operation)
+                .err()
+                .map(|error| rejection::ExtractError::InvalidOperation { index, error })
+        })
+}
+
+/// Receives a raw query sent as `application/graphql`, or as `text/plain` if enabled.
+async fn receive_graphql(
+    cx: &mut Context,
+    config: &GraphQLConfig,
+) -> Result<async_graphql::Request, ParseRequestError> {
+    let query = String::from_utf8(receive_body(cx, config).await?)
+        .map_err(|e| ParseRequestError::InvalidRequest(Box::new(e)))?;
+
+    if query.trim().is_empty() {
+        return Err(ParseRequestError::InvalidRequest(Box::from("empty query")));
+    }
+
+    Ok(async_graphql::Request::new(query))
+}
+
+/// Rejects multipart requests, which need the `uploads` feature.
+#[cfg(not(feature = "uploads"))]
+async fn receive_multipart(
+    _multipart: viz_core::types::Multipart,
+    _config: &GraphQLConfig,
+) -> Result<(async_graphql::BatchRequest, Vec<std::fs::File>), ParseRequestError> {
+    Err(ParseRequestError::InvalidRequest(Box::new(
+        rejection::ExtractError::MultipartNotSupported,
+    )))
+}
+
+/// Without the `uploads` feature there are no stored uploads to discard.
+#[cfg(not(feature = "uploads"))]
+async fn discard_uploads(_config: &GraphQLConfig, _files: Vec<std::fs::File>) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(method: http::Method, uri: &str, content_type: &str, body: Vec<u8>) -> Context {
+        let mut req = http::Request::new(http::Body::empty());
+        *req.method_mut() = method;
+        *req.uri_mut() = uri.parse().unwrap();
+        if !content_type.is_empty() {
+            req.headers_mut().insert(
+                http::header::CONTENT_TYPE,
+                http::HeaderValue::from_str(content_type).unwrap(),
+            );
+        }
+        req.headers_mut()
+            .insert(http::header::CONTENT_LENGTH, body.len().into());
+        *req.body_mut() = body.into();
+        Context::from(req)
+    }
+
+    async fn reject(mut cx: Context) -> (http::StatusCode, json::Value) {
+        let resp = http::Response::from(
+            GraphQLBatchRequest::extract(&mut cx)
+                .await
+                .err()
+                .unwrap()
+                .into_response(&cx),
+        );
+        let (parts, mut body) = resp.into_parts();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = viz_utils::futures::StreamExt::next(&mut body).await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        (parts.status, json::from_slice(&bytes).unwrap())
+    }
+
+    #[tokio::test]
+    async fn batches_are_checked() {
+        let batch = br#"[{"query":"{a}"},{"query":"{b}"}]"#.to_vec();
+
+        let mut cx = context(http::Method::POST, "/", "application/json", batch.clone());
+        cx.extensions_mut()
+            .insert(GraphQLConfig::new().batches(false));
+        let (status, body) = reject(cx).await;
+        assert_eq!(status, http::StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body["errors"][0]["message"],
+            "batch requests are not allowed"
+        );
+
+        let mut cx = context(http::Method::POST, "/", "application/json", batch);
+        cx.extensions_mut()
+            .insert(GraphQLConfig::new().max_batch_len(1));
+        let (status, body) = reject(cx).await;
+        assert_eq!(status, http::StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body["errors"][0]["message"],
+            "too many operations in batch request: 2 (max 1)"
+        );
+    }
+
+    #[tokio::test]
+    async fn limits_are_left_to_the_schema() {
+        let mut cx = context(http::Method::GET, "/?query=%7Ba%7Bb%7D%7D", "", Vec::new());
+        cx.extensions_mut()
+            .insert(GraphQLConfig::new().max_depth(1).max_complexity(1));
+
+        let request = GraphQLRequest::extract(&mut cx).await.ok().unwrap().0;
+        let checks = request
+            .data
+            .get(&std::any::TypeId::of::<RequestChecks>())
+            .and_then(|checks| checks.downcast_ref::<RequestChecks>())
+            .unwrap();
+        assert_eq!((checks.depth, checks.complexity), (Some(1), Some(1)));
+        assert!(checks.allowed_over_get.is_none() && checks.rate_limit.is_none());
+    }
+
+    #[tokio::test]
+    async fn get_without_query_keeps_the_persisted_query() {
+        let uri = "/?variables=%7B%22id%22%3A1%7D&extensions=%7B%22persistedQuery%22%3A%7B\
+            %22version%22%3A1%2C%22sha256Hash%22%3A%22abc%22%7D%7D";
+        let mut cx = context(http::Method::GET, uri, "", Vec::new());
+
+        let request = GraphQLRequest::extract(&mut cx).await.ok().unwrap().0;
+        assert_eq!(request.query, "");
+        assert_eq!(
+            json::to_value(&request.variables).unwrap(),
+            json::json!({ "id": 1 })
+        );
+        assert_eq!(
+            json::to_value(&request.extensions).unwrap(),
+            json::json!({ "persistedQuery": { "version": 1, "sha256Hash": "abc" } })
+        );
+    }
+
+    #[tokio::test]
+    async fn get_with_invalid_extensions_is_rejected() {
+        let cx = context(http::Method::GET, "/?extensions=%7B", "", Vec::new());
+        let (status, body) = reject(cx).await;
+        assert_eq!(status, http::StatusCode::BAD_REQUEST);
+        assert!(body["errors"][0]["message"]
+            .as_str()
+            .unwrap()
+            .contains("invalid `extensions` parameter"));
+    }
+
+    #[cfg(feature = "uploads")]
+    mod uploads {
+        use std::{
+            fs::File,
+            sync::{
+                atomic::{AtomicUsize, Ordering},
+                Arc,
+            },
+            time::Duration,
+        };
+
+        use super::*;
+        use crate::{rate_limit::OperationRateLimit, TempFileStorage, UploadStorage, UploadStream};
+
+        /// Counts the stored files which weren't discarded, and every stored file.
+        #[derive(Clone, Default)]
+        struct CountingStorage(Arc<AtomicUsize>, Arc<AtomicUsize>);
+
+        impl UploadStorage for CountingStorage {
+            fn store<'a>(
+                &'a self,
+                filename: &'a str,
+                content_type: Option<&'a str>,
+                content: UploadStream<'a>,
+            ) -> BoxFuture<'a, io::Result<File>> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                self.1.fetch_add(1, Ordering::SeqCst);
+                Box::pin(async move {
+                    TempFileStorage::new()
+                        .store(filename, content_type, content)
+                        .await
+                })
+            }
+
+            fn discard(&self, file: File) -> BoxFuture<'_, io::Result<()>> {
+                self.0.fetch_sub(1, Ordering::SeqCst);
+                drop(file);
+                Box::pin(async { Ok(()) })
+            }
+        }
+
+        const OPERATIONS: &str = "--abc\r\n\
+            Content-Disposition: form-data; name=\"operations\"\r\n\r\n\
+            {\"query\":\"mutation($f: Upload!) { upload(file: $f) }\",\"variables\":{\"f\":null}}\r\n";
+        const MAP: &str = "--abc\r\n\
+            Content-Disposition: form-data; name=\"map\"\r\n\r\n\
+            {\"0\":[\"variables.f\"]}\r\n";
+        const FILE: &str = "--abc\r\n\
+            Content-Disposition: form-data; name=\"0\"; filename=\"a.txt\"\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            hello\r\n";
+
+        fn multipart_context(parts: &[&str], config: GraphQLConfig) -> Context {
+            let body = format!("{}--abc--\r\n", parts.concat());
+            let mut cx = context(
+                http::Method::POST,
+                "/",
+                "multipart/form-data; boundary=abc",
+                body.into(),
+            );
+            cx.extensions_mut().insert(config);
+            cx
+        }
+
+        fn upload_context(config: GraphQLConfig) -> Context {
+            multipart_context(&[OPERATIONS, MAP, FILE], config)
+        }
+
+        #[tokio::test]
+        async fn extracted_uploads_are_kept() {
+            let storage = CountingStorage::default();
+            let mut cx = upload_context(GraphQLConfig::new().upload_storage(storage.clone()));
+
+            let request = GraphQLRequest::extract(&mut cx).await.ok().unwrap();
+            assert_eq!(request.0.uploads.len(), 1);
+            assert_eq!(storage.0.load(Ordering::SeqCst), 1);
+        }
+
+        #[tokio::test]
+        async fn rejected_uploads_are_discarded() {
+            let configs = vec![
+                GraphQLConfig::new().persisted_queries_only(true),
+                GraphQLConfig::new()
+                    .rate_limit(OperationRateLimit::new().default_limit(0, Duration::from_secs(1))),
+            ];
+            for config in configs {
+                let storage = CountingStorage::default();
+                let mut cx = upload_context(config.upload_storage(storage.clone()));
+
+                assert!(GraphQLRequest::extract(&mut cx).await.is_err());
+                assert_eq!(storage.0.load(Ordering::SeqCst), 0);
+            }
+        }
+
+        #[tokio::test]
+        async fn uploads_of_failed_multipart_requests_are_discarded() {
+            let invalid_map = "--abc\r\n\
+                Content-Disposition: form-data; name=\"map\"\r\n\r\n\
+                {\"0\":\r\n";
+            let unknown_file = "--abc\r\n\
+                Content-Disposition: form-data; name=\"map\"\r\n\r\n\
+                {\"1\":[\"variables.f\"]}\r\n";
+            let bodies = vec![
+                vec![OPERATIONS, FILE],
+                vec![OPERATIONS, FILE, invalid_map],
+                vec![OPERATIONS, FILE, unknown_file],
+            ];
+            for parts in bodies {
+                let storage = CountingStorage::default();
+                let config = GraphQLConfig::new().upload_storage(storage.clone());
+                let cx = multipart_context(&parts, config);
+
+                let (status, _) = reject(cx).await;
+                assert!(status.is_client_error(), "{}", status);
+                assert_eq!(storage.1.load(Ordering::SeqCst), 1);
+                assert_eq!(storage.0.load(Ordering::SeqCst), 0);
+            }
+        }
+    }
+}
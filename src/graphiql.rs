@@ -0,0 +1,181 @@
+use async_graphql::http::graphiql_source;
+
+use viz_core::Response;
+use viz_utils::serde::json::{self, Map, Value};
+
+/// Config for the GraphiQL page.
+#[derive(Debug, Clone)]
+pub struct GraphiQLConfig<'a> {
+    endpoint: &'a str,
+    subscription_endpoint: Option<&'a str>,
+    title: Option<&'a str>,
+    headers: Vec<(&'a str, &'a str)>,
+    nonce: Option<&'a str>,
+}
+
+impl<'a> GraphiQLConfig<'a> {
+    /// Creates a config for the GraphQL endpoint.
+    #[must_use]
+    pub fn new(endpoint: &'a str) -> Self {
+        Self {
+            endpoint,
+            subscription_endpoint: None,
+            title: None,
+            headers: Vec::new(),
+            nonce: None,
+        }
+    }
+
+    /// Sets the endpoint of subscriptions.
+    #[must_use]
+    pub fn subscription_endpoint(self, endpoint: &'a str) -> Self {
+        Self {
+            subscription_endpoint: Some(endpoint),
+            ..self
+        }
+    }
+
+    /// Sets the title of the page.
+    #[must_use]
+    pub fn title(self, title: &'a str) -> Self {
+        Self {
+            title: Some(title),
+            ..self
+        }
+    }
+
+    /// Adds a header sent with every request.
+    #[must_use]
+    pub fn with_header(mut self, name: &'a str, value: &'a str) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    /// Sets the nonce of the scripts and styles, to serve the page under a
+    /// `Content-Security-Policy` such as `script-src 'nonce-<nonce>'; style-src 'nonce-<nonce>'`.
+    ///
+    /// The nonce must be generated for every response, along with the header. The inline styles
+    /// of the page are moved into a style element, which carries the nonce too.
+    #[must_use]
+    pub fn nonce(self, nonce: &'a str) -> Self {
+        Self {
+            nonce: Some(nonce),
+            ..self
+        }
+    }
+}
+
+/// Serves the GraphiQL page for the endpoints.
+pub fn graphiql(endpoint: &str, subscription_endpoint: Option<&str>) -> Response {
+    let config = GraphiQLConfig::new(endpoint);
+    graphiql_with_config(match subscription_endpoint {
+        Some(subscription_endpoint) => config.subscription_endpoint(subscription_endpoint),
+        None => config,
+    })
+}
+
+/// Serves the GraphiQL page for the config.
+pub fn graphiql_with_config(config: GraphiQLConfig<'_>) -> Response {
+    let mut html = graphiql_source(config.endpoint, config.subscription_endpoint);
+
+    if let Some(title) = config.title {
+        html = html.replace(
+            "<title>Simple GraphiQL Example</title>",
+            &format!("<title>{}</title>", escape_html(title)),
+        );
+    }
+
+    if !config.headers.is_empty() {
+        let mut headers = Map::new();
+        headers.insert("Content-Type".to_owned(), "application/json".into());
+        for (name, value) in config.headers {
+            headers.insert(name.to_owned(), value.into());
+        }
+        // `<` is escaped so a value can't close the script element.
+        let headers = json::to_string(&Value::Object(headers))
+            .unwrap_or_default()
+            .replace('<', "\\u003c");
+        html = html.replace(
+            "headers: { 'Content-Type': 'application/json' },",
+            &format!("headers: {},", headers),
+        );
+    }
+
+    if let Some(nonce) = config.nonce {
+        let nonce = escape_html(nonce);
+        html = html
+            .replace(r#"<body style="margin: 0;">"#, "<body>")
+            .replace(
+                r#"<div id="graphiql" style="height: 100vh;">"#,
+                r#"<div id="graphiql">"#,
+            )
+            .replace(
+                "</head>",
+                "<style>body { margin: 0; } #graphiql { height: 100vh; }</style>\n  </head>",
+            )
+            .replace("<script", &format!(r#"<script nonce="{}""#, nonce))
+            .replace("<link", &format!(r#"<link nonce="{}""#, nonce))
+            .replace("<style", &format!(r#"<style nonce="{}""#, nonce));
+    }
+
+    Response::html(html)
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use viz_core::http;
+    use viz_utils::futures::StreamExt;
+
+    use super::*;
+
+    async fn html(config: GraphiQLConfig<'_>) -> String {
+        let mut body = http::Response::from(graphiql_with_config(config)).into_body();
+        let mut html = Vec::new();
+        while let Some(chunk) = body.next().await {
+            html.extend_from_slice(&chunk.unwrap());
+        }
+        String::from_utf8(html).unwrap()
+    }
+
+    #[tokio::test]
+    async fn title_and_headers_are_replaced() {
+        let html = html(
+            GraphiQLConfig::new("/graphql")
+                .title("<API>")
+                .with_header("Authorization", "Bearer </script>"),
+        )
+        .await;
+
+        assert!(html.contains("<title>&lt;API&gt;</title>"));
+        assert!(!html.contains("Simple GraphiQL Example"));
+        assert!(html.contains(
+            r#"headers: {"Authorization":"Bearer \u003c/script>","Content-Type":"application/json"},"#
+        ));
+        assert!(!html.contains("headers: { 'Content-Type': 'application/json' },"));
+    }
+
+    #[tokio::test]
+    async fn every_script_and_style_carries_the_nonce() {
+        let html = html(GraphiQLConfig::new("/graphql").nonce("abc")).await;
+
+        for tag in ["<script", "<link", "<style"] {
+            let count = html.matches(tag).count();
+            assert!(count > 0, "no {}", tag);
+            assert_eq!(
+                html.matches(&format!(r#"{} nonce="abc""#, tag)).count(),
+                count,
+                "{} without the nonce",
+                tag
+            );
+        }
+        assert!(!html.contains("style=\""));
+        assert!(html.contains("#graphiql { height: 100vh; }"));
+    }
+}
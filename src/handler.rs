@@ -0,0 +1,399 @@
+use std::{
+    any::TypeId,
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use async_graphql::{
+    parser::types::OperationType, BatchRequest, BatchResponse, ErrorExtensionValues, ObjectType,
+    Schema, ServerError, SubscriptionType,
+};
+use tokio::time;
+
+use viz_core::{
+    http::{HeaderMap, Method},
+    Context, Extract, Response,
+};
+use viz_utils::{
+    futures::{
+        future::{BoxFuture, Shared},
+        FutureExt, StreamExt,
+    },
+    serde::json::json,
+};
+
+use crate::{limits::RequestChecks, GraphQLBatchRequest, GraphQLConfig, GraphQLResponse};
+
+/// Extractor used by the [`graphql`] and [`graphql_first_response`] handlers.
+///
+/// It contains the batch request, and the configuration, method, headers and request id of the
+/// `Context` used to respond. Rejections are converted with
+/// [`GraphQLRejection::into_response`](crate::rejection::GraphQLRejection::into_response).
+pub struct GraphQLHandlerRequest {
+    request: async_graphql::BatchRequest,
+    operation_types: Vec<OperationType>,
+    config: GraphQLConfig,
+    method: Method,
+    headers: HeaderMap,
+    request_id: Option<String>,
+}
+
+impl GraphQLHandlerRequest {
+    /// Unwraps the value to `async_graphql::BatchRequest`.
+    #[must_use]
+    pub fn into_inner(self) -> async_graphql::BatchRequest {
+        self.request
+    }
+
+    /// Gets the types of the operations to be executed, see
+    /// [`GraphQLBatchRequest::operation_types`].
+    #[must_use]
+    pub fn operation_types(&self) -> &[OperationType] {
+        &self.operation_types
+    }
+}
+
+impl Extract for GraphQLHandlerRequest {
+    type Error = Response;
+
+    fn extract(cx: &mut Context) -> BoxFuture<'_, Result<Self, Self::Error>> {
+        Box::pin(async move {
+            match GraphQLBatchRequest::extract(cx).await {
+                Ok(GraphQLBatchRequest(request, operation_types)) => {
+                    let config = GraphQLConfig::get(cx);
+                    Ok(Self {
+                        request,
+                        operation_types,
+                        request_id: config.request_id.as_ref().and_then(|f| f(cx)),
+                        config,
+                        method: cx.method().clone(),
+                        headers: cx.headers().clone(),
+                    })
+                }
+                Err(rejection) => Err(rejection.into_response(cx)),
+            }
+        })
+    }
+}
+
+/// Creates a handler executing `GET` and `POST` GraphQL requests.
+///
+/// ```ignore
+/// .route(route("/graphql").get(graphql(schema.clone())).post(graphql(schema)))
+/// ```
+pub fn graphql<Query, Mutation, Subscription>(
+    schema: Schema<Query, Mutation, Subscription>,
+) -> impl Fn(GraphQLHandlerRequest) -> BoxFuture<'static, Response> + Clone + Send + Sync + 'static
+where
+    Query: ObjectType + 'static,
+    Mutation: ObjectType + 'static,
+    Subscription: SubscriptionType + 'static,
+{
+    move |req: GraphQLHandlerRequest| {
+        let schema = schema.clone();
+        Box::pin(async move { req.respond(|request| schema.execute_batch(request)).await })
+    }
+}
+
+/// Creates a handler executing `GET` and `POST` GraphQL requests, subscriptions included: a
+/// subscription is answered with its first response, then it's dropped.
+///
+/// It's a fallback for clients which can't open a WebSocket, e.g. to poll a subscription.
+/// Subscriptions are rejected over `GET` like mutations, see
+/// [`GraphQLConfig::reject_mutations_over_get`].
+///
+/// ```ignore
+/// .route(route("/graphql/first").post(graphql_first_response(schema)))
+/// ```
+pub fn graphql_first_response<Query, Mutation, Subscription>(
+    schema: Schema<Query, Mutation, Subscription>,
+) -> impl Fn(GraphQLHandlerRequest) -> BoxFuture<'static, Response> + Clone + Send + Sync + 'static
+where
+    Query: ObjectType + 'static,
+    Mutation: ObjectType + 'static,
+    Subscription: SubscriptionType + 'static,
+{
+    move |req: GraphQLHandlerRequest| {
+        let schema = schema.clone();
+        Box::pin(async move {
+            req.respond(|request| async move {
+                match request {
+                    BatchRequest::Single(request) => {
+                        BatchResponse::Single(first_response(&schema, request).await)
+                    }
+                    BatchRequest::Batch(requests) => {
+                        let mut resps = Vec::with_capacity(requests.len());
+                        for request in requests {
+                            resps.push(first_response(&schema, request).await);
+                        }
+                        BatchResponse::Batch(resps)
+                    }
+                }
+            })
+            .await
+        })
+    }
+}
+
+type InFlight =
+    Arc<Mutex<HashMap<String, Shared<BoxFuture<'static, Arc<async_graphql::Response>>>>>>;
+
+/// Creates a handler executing `GET` and `POST` GraphQL requests, like [`graphql`], which runs
+/// identical `GET` queries in flight at the same time only once.
+///
+/// Queries are identical when their operation name, query, variables and extensions are, the
+/// response of the first one is given to all of them. Queries carrying data, e.g. from a
+/// [`GraphQLData`](crate::GraphQLData) of a middleware, the
+/// [`RequestHeaders`](crate::RequestHeaders) or the [`ApolloClient`](crate::ApolloClient), are
+/// executed on their own, since their response may depend on the caller.
+///
+/// ```ignore
+/// .route(route("/graphql").get(graphql_single_flight(schema.clone())).post(graphql(schema)))
+/// ```
+pub fn graphql_single_flight<Query, Mutation, Subscription>(
+    schema: Schema<Query, Mutation, Subscription>,
+) -> impl Fn(GraphQLHandlerRequest) -> BoxFuture<'static, Response> + Clone + Send + Sync + 'static
+where
+    Query: ObjectType + 'static,
+    Mutation: ObjectType + 'static,
+    Subscription: SubscriptionType + 'static,
+{
+    let in_flight = InFlight::default();
+
+    move |req: GraphQLHandlerRequest| {
+        let schema = schema.clone();
+        let in_flight = in_flight.clone();
+        Box::pin(async move {
+            let deduplicated =
+                req.method == Method::GET && req.operation_types == [OperationType::Query];
+            if !deduplicated {
+                return req.respond(|request| schema.execute_batch(request)).await;
+            }
+
+            req.respond(|request| async move {
+                let request = match request {
+                    BatchRequest::Single(request) if is_caller_independent(&request) => request,
+                    batch => return schema.execute_batch(batch).await,
+                };
+                let key = single_flight_key(&request);
+                let execution = {
+                    let mut executions = in_flight.lock().unwrap();
+                    match executions.get(&key) {
+                        Some(execution) => {
+                            // The identical request in flight is checked in place of this one.
+                            if let Some(checks) = request_checks(&request) {
+                                checks.mark_seen();
+                            }
+                            execution.clone()
+                        }
+                        None => {
+                            let in_flight = in_flight.clone();
+                            let executed = key.clone();
+                            let execution = async move {
+                                let resp = schema.execute(request).await;
+                                in_flight.lock().unwrap().remove(&executed);
+                                Arc::new(resp)
+                            }
+                            .boxed()
+                            .shared();
+                            executions.insert(key, execution.clone());
+                            execution
+                        }
+                    }
+                };
+                BatchResponse::Single(clone_response(execution.await.as_ref()))
+            })
+            .await
+        })
+    }
+}
+
+/// Tells if the response of the request only depends on the request, i.e. it carries no data
+/// which may tell callers apart. The trace parent only tells the requests apart, the limits are
+/// part of the key.
+fn is_caller_independent(request: &async_graphql::Request) -> bool {
+    let checks = TypeId::of::<RequestChecks>();
+    #[cfg(feature = "tracing")]
+    let ignored = Some(TypeId::of::<crate::TraceParent>());
+    #[cfg(not(feature = "tracing"))]
+    let ignored = None;
+    request
+        .data
+        .keys()
+        .all(|id| *id == checks || Some(*id) == ignored)
+}
+
+/// Gets the key of identical requests, the variables are ordered, the extensions are sorted.
+fn single_flight_key(request: &async_graphql::Request) -> String {
+    let extensions = request.extensions.iter().collect::<BTreeMap<_, _>>();
+    let limits = request_checks(request).map(|checks| [checks.depth, checks.complexity]);
+    json!([
+        request.operation_name,
+        request.query,
+        request.variables,
+        extensions,
+        limits
+    ])
+    .to_string()
+}
+
+fn request_checks(request: &async_graphql::Request) -> Option<&RequestChecks> {
+    request
+        .data
+        .get(&TypeId::of::<RequestChecks>())
+        .and_then(|checks| checks.downcast_ref())
+}
+
+/// Clones a response shared by identical requests.
+fn clone_response(resp: &async_graphql::Response) -> async_graphql::Response {
+    let mut cloned = async_graphql::Response::new(resp.data.clone());
+    cloned.extensions = resp.extensions.clone();
+    cloned.cache_control = resp.cache_control;
+    cloned.errors = resp.errors.clone();
+    cloned.http_headers = resp.http_headers.clone();
+    cloned
+}
+
+impl GraphQLHandlerRequest {
+    /// Executes the request within the `execution_timeout`, and responds with the
+    /// configuration, method and headers of the request.
+    async fn respond<F, R>(self, execute: F) -> Response
+    where
+        F: FnOnce(BatchRequest) -> R,
+        R: Future<Output = BatchResponse>,
+    {
+        let len = match &self.request {
+            BatchRequest::Single(_) => None,
+            BatchRequest::Batch(requests) => Some(requests.len()),
+        };
+        let execute = execute(self.request);
+        let resp = match self.config.execution_timeout {
+            Some(timeout) => time::timeout(timeout, execute)
+                .await
+                .unwrap_or_else(|_| timed_out(len)),
+            None => execute.await,
+        };
+        let resp = match &self.request_id {
+            Some(id) => GraphQLResponse(resp).with_request_id(id),
+            None => GraphQLResponse(resp),
+        };
+        resp.respond(&self.config, Some((&self.method, &self.headers)))
+    }
+}
+
+/// Executes the request as a stream, and takes its first response.
+async fn first_response<Query, Mutation, Subscription>(
+    schema: &Schema<Query, Mutation, Subscription>,
+    request: async_graphql::Request,
+) -> async_graphql::Response
+where
+    Query: ObjectType + 'static,
+    Mutation: ObjectType + 'static,
+    Subscription: SubscriptionType + 'static,
+{
+    schema
+        .execute_stream(request)
+        .next()
+        .await
+        .unwrap_or_else(|| {
+            async_graphql::Response::from_errors(vec![ServerError::new(
+                "The subscription ended without a response.",
+                None,
+            )])
+        })
+}
+
+/// Creates the response of a request which timed out, with an error per operation of a batch.
+fn timed_out(len: Option<usize>) -> BatchResponse {
+    let resp = || {
+        let mut extensions = ErrorExtensionValues::default();
+        extensions.set("code", "TIMEOUT");
+        let mut err = ServerError::new("Execution timed out.", None);
+        err.extensions = Some(extensions);
+        async_graphql::Response::from_errors(vec![err])
+    };
+
+    match len {
+        Some(len) => BatchResponse::Batch((0..len).map(|_| resp()).collect()),
+        None => BatchResponse::Single(resp()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use async_graphql::{EmptyMutation, EmptySubscription, Object};
+    use viz_core::http;
+
+    use super::*;
+    use crate::GraphQLData;
+
+    #[derive(Clone)]
+    struct User(&'static str);
+
+    struct Query;
+
+    #[Object]
+    impl Query {
+        async fn user(&self, ctx: &async_graphql::Context<'_>) -> &'static str {
+            ctx.data_unchecked::<Arc<AtomicUsize>>()
+                .fetch_add(1, Ordering::SeqCst);
+            time::sleep(Duration::from_millis(50)).await;
+            ctx.data_opt::<User>().map_or("anonymous", |user| user.0)
+        }
+    }
+
+    async fn get(
+        handler: impl Fn(GraphQLHandlerRequest) -> BoxFuture<'static, Response>,
+        user: Option<&'static str>,
+    ) -> String {
+        let mut req = http::Request::new(http::Body::empty());
+        *req.uri_mut() = "/?query=%7Buser%7D".parse().unwrap();
+        let mut cx = Context::from(req);
+        if let Some(user) = user {
+            cx.extensions_mut()
+                .insert(GraphQLData::new().with(User(user)));
+        }
+
+        let req = GraphQLHandlerRequest::extract(&mut cx).await.ok().unwrap();
+        let mut body = http::Response::from(handler(req).await).into_body();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.next().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        String::from_utf8(bytes).unwrap()
+    }
+
+    fn schema(executions: Arc<AtomicUsize>) -> Schema<Query, EmptyMutation, EmptySubscription> {
+        Schema::build(Query, EmptyMutation, EmptySubscription)
+            .data(executions)
+            .finish()
+    }
+
+    #[tokio::test]
+    async fn identical_queries_are_executed_once() {
+        let executions = Arc::new(AtomicUsize::new(0));
+        let handler = graphql_single_flight(schema(executions.clone()));
+
+        let (a, b) = tokio::join!(get(handler.clone(), None), get(handler, None));
+        assert_eq!(a, r#"{"data":{"user":"anonymous"}}"#);
+        assert_eq!(a, b);
+        assert_eq!(executions.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn queries_with_data_are_executed_on_their_own() {
+        let executions = Arc::new(AtomicUsize::new(0));
+        let handler = graphql_single_flight(schema(executions.clone()));
+
+        let (a, b) = tokio::join!(get(handler.clone(), Some("a")), get(handler, Some("b")));
+        assert_eq!(a, r#"{"data":{"user":"a"}}"#);
+        assert_eq!(b, r#"{"data":{"user":"b"}}"#);
+        assert_eq!(executions.load(Ordering::SeqCst), 2);
+    }
+}
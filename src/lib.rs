@@ -2,12 +2,59 @@
 #![forbid(unsafe_code)]
 #![warn(missing_docs)]
 
+#[cfg(feature = "compression")]
+mod compression;
+mod config;
+mod data;
 mod extract;
+mod graphiql;
+mod handler;
+mod limits;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "uploads")]
+mod multipart;
+#[cfg(feature = "apollo_persisted_queries")]
+mod persisted_queries;
+mod playground;
+mod rate_limit;
 mod response;
+#[cfg(feature = "router")]
+mod router;
+mod sdl;
+#[cfg(feature = "sse")]
+pub mod sse;
 mod subscription;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+#[cfg(feature = "uploads")]
+mod upload;
 
-pub use extract::{GraphQLBatchRequest, GraphQLRequest};
-pub use response::GraphQLResponse;
+pub use config::GraphQLConfig;
+pub use data::GraphQLData;
+#[cfg(feature = "tracing")]
+pub use extract::TraceParent;
+#[cfg(feature = "uploads")]
+pub use extract::UnmappedFiles;
+pub use extract::{
+    rejection, ApolloClient, GraphQLBatchRequest, GraphQLRawBody, GraphQLRequest,
+    GraphQLRequestKind, GraphQLRequestWithHeaders, RequestHeaders,
+};
+pub use graphiql::{graphiql, graphiql_with_config, GraphiQLConfig};
+pub use handler::{graphql, graphql_first_response, graphql_single_flight, GraphQLHandlerRequest};
+pub use limits::GraphQLLimits;
+#[cfg(feature = "apollo_persisted_queries")]
+pub use persisted_queries::persisted_queries;
+pub use playground::playground;
+pub use rate_limit::OperationRateLimit;
+pub use response::{GraphQLResponse, GraphQLStreamResponse, IntoGraphQLResponse, JsonSerializer};
+#[cfg(feature = "router")]
+pub use router::GraphQLRoutes;
+pub use sdl::{federation_sdl, sdl};
 pub use subscription::{
-    graphql_subscription, graphql_subscription_with_data, SecWebsocketProtocol,
+    graphql_subscription, graphql_subscription_with_cookies, graphql_subscription_with_data,
+    graphql_subscription_with_headers, graphql_subscription_with_shutdown, GraphQLSubscription,
+    OverflowPolicy, SecWebsocketProtocol, SubscriptionError,
 };
+#[cfg(feature = "uploads")]
+pub use upload::{TempFileStorage, UploadStorage, UploadStream};
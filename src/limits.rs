@@ -0,0 +1,397 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Once,
+};
+
+use async_graphql::{
+    async_trait::async_trait,
+    extensions::{
+        Extension, ExtensionContext, ExtensionFactory, NextPrepareRequest, NextValidation,
+    },
+    parser::{parse_query, types::OperationType},
+    ErrorExtensionValues, Request, ServerError, ServerResult, ValidationResult,
+};
+use viz_utils::tracing;
+
+use crate::{
+    extract::{find_operation, operation_name},
+    OperationRateLimit,
+};
+
+/// The checks of a request which need the schema, attached by the extractors from the
+/// [`GraphQLConfig`](crate::GraphQLConfig).
+#[derive(Debug)]
+pub(crate) struct RequestChecks {
+    pub(crate) depth: Option<usize>,
+    pub(crate) complexity: Option<usize>,
+    /// The operation types allowed for a `GET` request only carrying the hash of a persisted
+    /// query, whose operation is unknown until the schema looks the hash up.
+    pub(crate) allowed_over_get: Option<&'static [OperationType]>,
+    /// The rate limit of a request only carrying the hash of a persisted query, whose operation
+    /// name is unknown until the schema looks the hash up.
+    pub(crate) rate_limit: Option<Arc<OperationRateLimit>>,
+    seen: Seen,
+}
+
+impl RequestChecks {
+    /// Creates the checks of a request, `None` if there is nothing to check.
+    pub(crate) fn new(
+        depth: Option<usize>,
+        complexity: Option<usize>,
+        allowed_over_get: Option<&'static [OperationType]>,
+        rate_limit: Option<Arc<OperationRateLimit>>,
+    ) -> Option<Self> {
+        if depth.is_none()
+            && complexity.is_none()
+            && allowed_over_get.is_none()
+            && rate_limit.is_none()
+        {
+            return None;
+        }
+
+        Some(Self {
+            depth,
+            complexity,
+            allowed_over_get,
+            rate_limit,
+            seen: Seen::default(),
+        })
+    }
+
+    /// Marks the checks as handled, e.g. for a request answered by an identical one.
+    pub(crate) fn mark_seen(&self) {
+        self.seen.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Warns once if checks are dropped without being seen by a [`GraphQLLimits`] extension.
+#[derive(Debug, Default)]
+struct Seen(AtomicBool);
+
+impl Drop for Seen {
+    fn drop(&mut self) {
+        static WARNED: Once = Once::new();
+
+        if !*self.0.get_mut() {
+            WARNED.call_once(|| {
+                tracing::warn!(
+                    "a GraphQL request was dropped without its depth, complexity, GET or rate \
+                     limit checks, register the `GraphQLLimits` extension on the schema"
+                );
+            });
+        }
+    }
+}
+
+/// An extension enforcing the checks of the [`GraphQLConfig`](crate::GraphQLConfig) of each
+/// request which need the schema.
+///
+/// The depth and complexity of `max_depth` and `max_complexity` are the ones computed by the
+/// schema, so the `complexity` of the fields is counted. Operations over the limits fail
+/// validation with the errors of `Schema::limit_depth` and `Schema::limit_complexity`.
+///
+/// The operation of a `GET` request only carrying the hash of a persisted query is checked
+/// against `reject_mutations_over_get` once the `ApolloPersistedQueries` extension looked the
+/// hash up, whatever their order. Operations which aren't allowed fail with a `405` status
+/// extension.
+///
+/// The rate limit of a request only carrying the hash of a persisted query is charged once the
+/// hash was looked up, to the bucket of the `operationName` of the request, or of the name of the
+/// only operation of the persisted query. Operations over their limit fail with a `429` status
+/// and a `retryAfter` extension, in seconds.
+///
+/// Without the extension, these checks are silently skipped, a warning is logged once when a
+/// request is dropped without them.
+///
+/// ```ignore
+/// let schema = Schema::build(Query, EmptyMutation, EmptySubscription)
+///     .extension(GraphQLLimits)
+///     .finish();
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GraphQLLimits;
+
+impl ExtensionFactory for GraphQLLimits {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(GraphQLLimits)
+    }
+}
+
+#[async_trait]
+impl Extension for GraphQLLimits {
+    async fn prepare_request(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        request: Request,
+        next: NextPrepareRequest<'_>,
+    ) -> ServerResult<Request> {
+        if let Some(checks) = ctx.data_opt::<RequestChecks>() {
+            checks.mark_seen();
+        }
+
+        let request = next.run(ctx, request).await?;
+
+        let checks = match ctx.data_opt::<RequestChecks>() {
+            Some(checks) if checks.allowed_over_get.is_some() || checks.rate_limit.is_some() => {
+                checks
+            }
+            _ => return Ok(request),
+        };
+
+        // Queries which can't be parsed are left to the schema.
+        let document = parse_query(&request.query).ok();
+        let operation = document
+            .as_ref()
+            .and_then(|document| find_operation(document, request.operation_name.as_deref()));
+
+        if let (Some(allowed_over_get), Some(operation)) = (checks.allowed_over_get, operation) {
+            if !allowed_over_get.contains(&operation.ty) {
+                return Err(error(
+                    format!("{} operations are not allowed over GET", operation.ty),
+                    405,
+                ));
+            }
+        }
+        if let Some(rate_limit) = &checks.rate_limit {
+            let operation_name = operation_name(&request, document.as_ref());
+            if let Err(retry_after) = rate_limit.check(operation_name.as_deref()) {
+                let message = match &operation_name {
+                    Some(operation) => {
+                        format!("rate limit exceeded for operation `{}`", operation)
+                    }
+                    None => "rate limit exceeded".to_owned(),
+                };
+                let mut err = error(message, 429);
+                if let Some(extensions) = &mut err.extensions {
+                    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                    extensions.set("retryAfter", secs);
+                }
+                return Err(err);
+            }
+        }
+
+        Ok(request)
+    }
+
+    async fn validation(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        next: NextValidation<'_>,
+    ) -> Result<ValidationResult, Vec<ServerError>> {
+        let result = next.run(ctx).await?;
+
+        if let Some(limits) = ctx.data_opt::<RequestChecks>() {
+            if limits
+                .complexity
+                .map_or(false, |max| result.complexity > max)
+            {
+                return Err(vec![ServerError::new("Query is too complex.", None)]);
+            }
+            if limits.depth.map_or(false, |max| result.depth > max) {
+                return Err(vec![ServerError::new("Query is nested too deep.", None)]);
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+/// Creates an error with a `status` extension.
+fn error(message: String, status: u16) -> ServerError {
+    let mut extensions = ErrorExtensionValues::default();
+    extensions.set("status", status);
+    let mut err = ServerError::new(message, None);
+    err.extensions = Some(extensions);
+    err
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::{EmptyMutation, EmptySubscription, Object, Schema, SimpleObject};
+
+    use super::*;
+
+    #[derive(SimpleObject)]
+    struct Item {
+        value: i32,
+    }
+
+    struct Query;
+
+    #[Object]
+    impl Query {
+        #[graphql(complexity = 10)]
+        async fn expensive(&self) -> i32 {
+            1
+        }
+
+        async fn item(&self) -> Item {
+            Item { value: 1 }
+        }
+    }
+
+    fn schema() -> Schema<Query, EmptyMutation, EmptySubscription> {
+        Schema::build(Query, EmptyMutation, EmptySubscription)
+            .extension(GraphQLLimits)
+            .finish()
+    }
+
+    fn limited(query: &str, depth: Option<usize>, complexity: Option<usize>) -> Request {
+        Request::new(query).data(RequestChecks::new(depth, complexity, None, None).unwrap())
+    }
+
+    fn messages(resp: async_graphql::Response) -> Vec<String> {
+        resp.errors.into_iter().map(|err| err.message).collect()
+    }
+
+    #[tokio::test]
+    async fn complexity_counts_the_fields_costs() {
+        let resp = schema()
+            .execute(limited("{ expensive }", None, Some(5)))
+            .await;
+        assert_eq!(messages(resp), ["Query is too complex."]);
+
+        let resp = schema()
+            .execute(limited("{ expensive }", None, Some(10)))
+            .await;
+        assert!(resp.is_ok());
+    }
+
+    #[tokio::test]
+    async fn depth_is_limited() {
+        let resp = schema()
+            .execute(limited("{ item { value } }", Some(1), None))
+            .await;
+        assert_eq!(messages(resp), ["Query is nested too deep."]);
+
+        let resp = schema()
+            .execute(limited("{ item { value } }", Some(2), None))
+            .await;
+        assert!(resp.is_ok());
+    }
+
+    #[tokio::test]
+    async fn requests_without_limits_are_unlimited() {
+        let resp = schema().execute("{ expensive item { value } }").await;
+        assert!(resp.is_ok());
+    }
+
+    #[cfg(feature = "apollo_persisted_queries")]
+    mod persisted {
+        use std::time::Duration;
+
+        use async_graphql::{
+            extensions::apollo_persisted_queries::{ApolloPersistedQueries, LruCacheStorage},
+            EmptySubscription, Object, Schema, Value,
+        };
+        use viz_core::{http, Context, Extract};
+        use viz_utils::serde::json::{self, json};
+
+        use super::*;
+        use crate::{GraphQLConfig, GraphQLRequest};
+
+        struct Query;
+
+        #[Object]
+        impl Query {
+            async fn value(&self) -> i32 {
+                1
+            }
+        }
+
+        struct Mutation;
+
+        #[Object]
+        impl Mutation {
+            async fn value(&self) -> i32 {
+                2
+            }
+        }
+
+        async fn persisted(query: &str) -> Schema<Query, Mutation, EmptySubscription> {
+            let schema = Schema::build(Query, Mutation, EmptySubscription)
+                .extension(ApolloPersistedQueries::new(LruCacheStorage::new(16)))
+                .extension(GraphQLLimits)
+                .finish();
+            let mut request = Request::new(query);
+            request.extensions.insert(
+                "persistedQuery".to_owned(),
+                Value::from_json(json!({ "version": 1, "sha256Hash": hash(query) })).unwrap(),
+            );
+            assert!(schema.execute(request).await.is_ok());
+            schema
+        }
+
+        fn hash(query: &str) -> &'static str {
+            match query {
+                "{ value }" => "854174ebed716fe24fd6659c30290aecd9bc1d17dc4f47939a1848a1b8ed3c6b",
+                "mutation { value }" => {
+                    "fab32abc5a696bf85b76fcef81c126078a370a7b1cce7ce91349ac8f9d90fdde"
+                }
+                "query GenerateReport { value }" => {
+                    "76ef73bee71b8ca05438b33326944cc42c482999430b2975252e232968f284f6"
+                }
+                _ => unreachable!(),
+            }
+        }
+
+        async fn get(query: &str, config: GraphQLConfig) -> json::Value {
+            let schema = persisted(query).await;
+            let mut req = http::Request::new(http::Body::empty());
+            *req.uri_mut() = format!(
+                "/?extensions=%7B%22persistedQuery%22%3A%7B%22version%22%3A1%2C\
+                 %22sha256Hash%22%3A%22{}%22%7D%7D",
+                hash(query)
+            )
+            .parse()
+            .unwrap();
+            let mut cx = Context::from(req);
+            cx.extensions_mut().insert(config);
+
+            let request = GraphQLRequest::extract(&mut cx).await.ok().unwrap();
+            json::to_value(schema.execute(request.into_inner()).await).unwrap()
+        }
+
+        #[tokio::test]
+        async fn persisted_mutations_are_rejected_over_get() {
+            let resp = get("mutation { value }", GraphQLConfig::new()).await;
+            assert_eq!(
+                resp["errors"][0]["message"],
+                "mutation operations are not allowed over GET"
+            );
+            assert_eq!(resp["errors"][0]["extensions"]["status"], 405);
+            assert_eq!(resp["data"], json::Value::Null);
+
+            let config = GraphQLConfig::new().reject_mutations_over_get(false);
+            let resp = get("mutation { value }", config).await;
+            assert_eq!(resp["data"], json!({ "value": 2 }));
+        }
+
+        #[tokio::test]
+        async fn persisted_operations_are_rate_limited_by_their_name() {
+            let config = GraphQLConfig::new().rate_limit(
+                OperationRateLimit::new()
+                    .operation("GenerateReport", 1, Duration::from_secs(60))
+                    .default_limit(10, Duration::from_secs(1)),
+            );
+
+            let resp = get("query GenerateReport { value }", config.clone()).await;
+            assert_eq!(resp["data"], json!({ "value": 1 }));
+
+            // The request has no `operationName`, the persisted operation is named.
+            let resp = get("query GenerateReport { value }", config).await;
+            assert_eq!(
+                resp["errors"][0]["message"],
+                "rate limit exceeded for operation `GenerateReport`"
+            );
+            assert_eq!(resp["errors"][0]["extensions"]["status"], 429);
+            assert_eq!(resp["errors"][0]["extensions"]["retryAfter"], 60);
+        }
+
+        #[tokio::test]
+        async fn persisted_queries_are_allowed_over_get() {
+            let resp = get("{ value }", GraphQLConfig::new()).await;
+            assert_eq!(resp["data"], json!({ "value": 1 }));
+        }
+    }
+}
@@ -0,0 +1,39 @@
+//! Metrics recorded with the `metrics` facade, named after the prefix of the configuration.
+//!
+//! * `<prefix>_requests_total`: extracted requests.
+//! * `<prefix>_rejections_total`: rejected requests, e.g. parse errors.
+//! * `<prefix>_batch_size`: histogram of the number of operations of the requests.
+//! * `<prefix>_upload_bytes_total`: uploaded bytes.
+//! * `<prefix>_errors_total`: errors of the responses.
+
+use crate::GraphQLConfig;
+
+pub(crate) fn request(config: &GraphQLConfig, batch: &async_graphql::BatchRequest) {
+    let requests = format!("{}_requests_total", config.metrics_prefix);
+    let batch_size = format!("{}_batch_size", config.metrics_prefix);
+    let len = match batch {
+        async_graphql::BatchRequest::Single(_) => 1,
+        async_graphql::BatchRequest::Batch(requests) => requests.len(),
+    };
+
+    ::metrics::counter!(requests, 1);
+    ::metrics::histogram!(batch_size, len as f64);
+}
+
+pub(crate) fn rejection(config: &GraphQLConfig) {
+    let rejections = format!("{}_rejections_total", config.metrics_prefix);
+    ::metrics::counter!(rejections, 1);
+}
+
+#[cfg(feature = "uploads")]
+pub(crate) fn upload(config: &GraphQLConfig, size: u64) {
+    let upload_bytes = format!("{}_upload_bytes_total", config.metrics_prefix);
+    ::metrics::counter!(upload_bytes, size);
+}
+
+pub(crate) fn response(config: &GraphQLConfig, errors: usize) {
+    if errors > 0 {
+        let name = format!("{}_errors_total", config.metrics_prefix);
+        ::metrics::counter!(name, errors as u64);
+    }
+}
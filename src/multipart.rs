@@ -0,0 +1,404 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use async_graphql::ParseRequestError;
+use bytes::Bytes;
+use form_data::FormDataError;
+use viz_core::{types::Multipart, Result};
+#[cfg(feature = "tracing")]
+use viz_utils::tracing;
+use viz_utils::{
+    futures::{Stream, StreamExt, TryStreamExt},
+    serde::json,
+};
+
+use crate::{
+    upload::{TempFileStorage, UploadStorage},
+    GraphQLConfig, UnmappedFiles,
+};
+
+/// Receives a multipart request, following the
+/// [GraphQL multipart request spec](https://github.com/jaydenseric/graphql-multipart-request-spec).
+///
+/// Returns the stored files along with the request, to be discarded with [`discard_uploads`] if
+/// it's rejected afterwards.
+pub(crate) async fn receive_multipart(
+    multipart: Multipart,
+    config: &GraphQLConfig,
+) -> Result<(async_graphql::BatchRequest, Vec<File>), ParseRequestError> {
+    if let Ok(mut state) = multipart.state().lock() {
+        let limits = state.limits_mut();
+        // The file size is enforced while copying, to answer with `PayloadTooLarge`.
+        limits.file_size = None;
+        limits.files = config.multipart.max_num_files;
+        limits.fields = Some(config.max_fields);
+        limits.field_size = Some(config.max_part_size);
+        limits.stream_size = Some(config.max_request_size);
+    }
+
+    receive_batch_multipart(multipart, config)
+        .await
+        .map_err(|e| match e.downcast::<ParseRequestError>() {
+            Ok(e) => e,
+            Err(e) => match e.downcast_ref::<FormDataError>() {
+                Some(
+                    FormDataError::PayloadTooLarge(_)
+                    | FormDataError::FieldTooLarge(_)
+                    | FormDataError::FileTooLarge(_),
+                ) => ParseRequestError::PayloadTooLarge,
+                _ => ParseRequestError::InvalidRequest(Box::from(e)),
+            },
+        })
+}
+
+/// A file stored from a multipart request: its field name, filename, content type and file.
+type StoredFile = (String, String, Option<String>, File);
+
+/// Discards the stored files of a rejected request, the rejection is returned rather than the
+/// errors of the cleanup.
+pub(crate) async fn discard_uploads(config: &GraphQLConfig, files: Vec<File>) {
+    if files.is_empty() {
+        return;
+    }
+    let storage = upload_storage(config);
+    for file in files {
+        let _ = storage.discard(file).await;
+    }
+}
+
+fn upload_storage(config: &GraphQLConfig) -> Arc<dyn UploadStorage> {
+    match &config.upload_storage {
+        Some(storage) => storage.clone(),
+        None => Arc::new(
+            match &config.upload_dir {
+                Some(dir) => TempFileStorage::in_dir(dir),
+                None => TempFileStorage::new(),
+            }
+            .write_buffer_size(config.upload_write_buffer_size),
+        ),
+    }
+}
+
+async fn receive_batch_multipart(
+    multipart: Multipart,
+    config: &GraphQLConfig,
+) -> Result<(async_graphql::BatchRequest, Vec<File>)> {
+    let storage = upload_storage(config);
+
+    let mut files = Vec::new();
+    let parts = read_fields(multipart, config, storage.as_ref(), &mut files)
+        .await
+        .and_then(|(request, map)| {
+            check_files_map(&request, &map)?;
+            if !map
+                .keys()
+                .all(|name| files.iter().any(|file| &file.0 == name))
+            {
+                return Err(ParseRequestError::MissingFiles.into());
+            }
+            Ok((request, map))
+        });
+    let (mut request, mut map) = match parts {
+        Ok(parts) => parts,
+        Err(err) => {
+            // The error of the request is returned, rather than the errors of the cleanup.
+            for (_, _, _, file) in files {
+                let _ = storage.discard(file).await;
+            }
+            return Err(err);
+        }
+    };
+
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record("files", files.len());
+
+    let mut unmapped = Vec::new();
+    // The request gets handles of the files, the stored ones are kept to be discarded.
+    let mut stored = Vec::with_capacity(files.len());
+
+    for (name, filename, content_type, content) in files {
+        let upload = async_graphql::UploadValue {
+            filename,
+            content_type,
+            content: content.try_clone()?,
+        };
+        stored.push(content);
+
+        if let Some(var_paths) = map.remove(&name) {
+            for var_path in var_paths {
+                match &mut request {
+                    async_graphql::BatchRequest::Single(request) => {
+                        request.set_upload(&var_path, upload.try_clone()?);
+                    }
+                    async_graphql::BatchRequest::Batch(requests) => {
+                        let mut s = var_path.splitn(2, '.');
+                        let idx = s.next().and_then(|idx| idx.parse::<usize>().ok());
+                        let path = s.next();
+
+                        if let (Some(idx), Some(path)) = (idx, path) {
+                            if let Some(request) = requests.get_mut(idx) {
+                                request.set_upload(path, upload.try_clone()?);
+                            }
+                        }
+                    }
+                }
+            }
+        } else if config.unmapped_files {
+            unmapped.push(upload);
+        }
+    }
+
+    if !unmapped.is_empty() {
+        match &mut request {
+            async_graphql::BatchRequest::Single(request) => {
+                request.data.insert(UnmappedFiles(unmapped));
+            }
+            async_graphql::BatchRequest::Batch(requests) => {
+                for request in requests {
+                    let files = unmapped
+                        .iter()
+                        .map(async_graphql::UploadValue::try_clone)
+                        .collect::<std::io::Result<_>>()?;
+                    request.data.insert(UnmappedFiles(files));
+                }
+            }
+        }
+    }
+
+    Ok((request, stored))
+}
+
+/// Reads the fields of a multipart request, storing the files into `files` as they're read so
+/// they can be discarded if it fails.
+async fn read_fields(
+    mut multipart: Multipart,
+    config: &GraphQLConfig,
+    storage: &dyn UploadStorage,
+    files: &mut Vec<StoredFile>,
+) -> Result<(async_graphql::BatchRequest, HashMap<String, Vec<String>>)> {
+    let mut request = None;
+    let mut map = None;
+    let mut uploads_size = 0;
+
+    while let Some(mut field) = multipart.try_next().await? {
+        // in multipart, each field / file can actually have a own Content-Type.
+        // We use this to determine the encoding of the graphql query
+        let content_type = field
+            .content_type
+            .to_owned()
+            .unwrap_or(mime::APPLICATION_JSON);
+
+        let name = field.name.clone();
+
+        match name.as_str() {
+            "operations" => {
+                let body = read_part(&mut field, config.max_part_size).await?;
+
+                match (content_type.type_(), content_type.subtype()) {
+                    // Like the map below, the operations may be sent as CBOR.
+                    #[cfg(feature = "cbor")]
+                    (mime::OCTET_STREAM, _) | (mime::APPLICATION, mime::OCTET_STREAM) => {
+                        request = Some(
+                            serde_cbor::from_slice(&body)
+                                .map_err(|e| ParseRequestError::InvalidRequest(Box::new(e)))?,
+                        );
+                    }
+                    // default to json
+                    _ => request = Some(json::from_str(json_part(&body, "operations")?)?),
+                }
+            }
+            "map" => {
+                let map_bytes = read_part(&mut field, config.max_part_size).await?;
+
+                match (content_type.type_(), content_type.subtype()) {
+                    // cbor is in application/octet-stream.
+                    // TODO: wait for mime to add application/cbor and match against that too
+                    // Note: we actually differ here from the inoffical spec for this:
+                    // (https://github.com/jaydenseric/graphql-multipart-request-spec#multipart-form-field-structure)
+                    // It says: "map: A JSON encoded map of where files occurred in the operations.
+                    // For each file, the key is the file multipart form field name and the value is an array of operations paths."
+                    // However, I think, that since we accept CBOR as operation, which is valid, we should also accept it
+                    // as the mapping for the files.
+                    #[cfg(feature = "cbor")]
+                    (mime::OCTET_STREAM, _) | (mime::APPLICATION, mime::OCTET_STREAM) => {
+                        map = Some(
+                            serde_cbor::from_slice::<HashMap<String, Vec<String>>>(&map_bytes)
+                                .map_err(|e| ParseRequestError::InvalidFilesMap(Box::new(e)))?,
+                        );
+                    }
+                    // default to json
+                    _ => {
+                        map = Some(
+                            json::from_str::<HashMap<String, Vec<String>>>(json_part(
+                                &map_bytes, "map",
+                            )?)
+                            .map_err(|e| ParseRequestError::InvalidFilesMap(Box::new(e)))?,
+                        );
+                    }
+                }
+            }
+            _ => match field.filename.to_owned() {
+                Some(filename) if !name.is_empty() => {
+                    let content_type = content_type.to_string();
+                    // The file is bounded by what's left of the total size too.
+                    let max_size = config
+                        .max_uploads_size
+                        .map(|max| max.saturating_sub(uploads_size))
+                        .into_iter()
+                        .chain(config.multipart.max_file_size.map(|max| max as u64))
+                        .min();
+                    let (file, size) =
+                        store_upload(&mut field, &filename, &content_type, storage, max_size)
+                            .await?;
+                    uploads_size += size;
+                    #[cfg(feature = "metrics")]
+                    crate::metrics::upload(config, size);
+                    files.push((name, filename, Some(content_type), file));
+                }
+                // Other fields are skipped, they must still be read to get the next one.
+                _ => field.ignore().await?,
+            },
+        }
+    }
+
+    let request = request.ok_or(ParseRequestError::MissingOperatorsPart)?;
+    let map = map.ok_or(ParseRequestError::MissingMapPart)?;
+
+    Ok((request, map))
+}
+
+/// Reads the `operations` or `map` part, failing as soon as it's larger than `max_part_size`.
+async fn read_part<S>(field: &mut S, max_part_size: usize) -> Result<Vec<u8>>
+where
+    S: Stream<Item = Result<Bytes>> + Unpin,
+{
+    let mut data = Vec::new();
+
+    while let Some(chunk) = field.try_next().await? {
+        if data.len() + chunk.len() > max_part_size {
+            return Err(ParseRequestError::PayloadTooLarge.into());
+        }
+        data.extend_from_slice(&chunk);
+    }
+
+    Ok(data)
+}
+
+/// Checks that a JSON part is valid UTF-8, so a binary part sent by mistake is reported as such
+/// rather than as a JSON syntax error.
+fn json_part<'a>(data: &'a [u8], name: &str) -> Result<&'a str, ParseRequestError> {
+    std::str::from_utf8(data).map_err(|e| {
+        ParseRequestError::InvalidRequest(Box::from(format!(
+            "the `{}` part isn't valid UTF-8: {}",
+            name, e
+        )))
+    })
+}
+
+/// Stores an uploaded file, failing as soon as it's larger than `max_size`.
+///
+/// Returns the file and its size.
+async fn store_upload<S>(
+    field: &mut S,
+    filename: &str,
+    content_type: &str,
+    storage: &dyn UploadStorage,
+    max_size: Option<u64>,
+) -> Result<(File, u64)>
+where
+    S: Stream<Item = Result<Bytes>> + Send + Unpin,
+{
+    let size = AtomicU64::new(0);
+    // The storage only sees `io::Error`s, the errors of the body are kept to be returned as is.
+    let error = Mutex::new(None);
+
+    let content = field.map(|chunk| match chunk {
+        Ok(chunk) => {
+            let len = chunk.len() as u64;
+            let total = size.fetch_add(len, Ordering::Relaxed) + len;
+            if max_size.map_or(false, |max| total > max) {
+                *error.lock().unwrap() = Some(ParseRequestError::PayloadTooLarge.into());
+                return Err(io::Error::new(io::ErrorKind::Other, "file too large"));
+            }
+            Ok(chunk)
+        }
+        Err(err) => {
+            let io_error = io::Error::new(io::ErrorKind::Other, err.to_string());
+            *error.lock().unwrap() = Some(err);
+            Err(io_error)
+        }
+    });
+    let file = storage
+        .store(filename, Some(content_type), Box::pin(content))
+        .await;
+
+    if let Some(err) = error.into_inner().unwrap() {
+        return Err(err);
+    }
+    Ok((file.map_err(ParseRequestError::Io)?, size.into_inner()))
+}
+
+/// Checks that every path of the files map references a variable of the operations, otherwise
+/// `set_upload` would silently ignore it.
+fn check_files_map(
+    batch: &async_graphql::BatchRequest,
+    map: &HashMap<String, Vec<String>>,
+) -> Result<(), ParseRequestError> {
+    for var_path in map.values().flatten() {
+        let exists = match batch {
+            async_graphql::BatchRequest::Single(request) => {
+                variable_exists(&request.variables, var_path)
+            }
+            async_graphql::BatchRequest::Batch(requests) => {
+                let mut s = var_path.splitn(2, '.');
+                let request = s
+                    .next()
+                    .and_then(|idx| idx.parse::<usize>().ok())
+                    .and_then(|idx| requests.get(idx));
+
+                match (request, s.next()) {
+                    (Some(request), Some(path)) => variable_exists(&request.variables, path),
+                    _ => false,
+                }
+            }
+        };
+
+        if !exists {
+            return Err(ParseRequestError::InvalidFilesMap(Box::from(format!(
+                "`{}` doesn't reference a variable of the operations",
+                var_path
+            ))));
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolves the path like `Request::set_upload` does.
+fn variable_exists(variables: &async_graphql::Variables, var_path: &str) -> bool {
+    let mut parts = match var_path.strip_prefix("variables.") {
+        Some(path) => path.split('.'),
+        None => return false,
+    };
+    let initial = match parts.next().and_then(|name| variables.get(name)) {
+        Some(value) => value,
+        None => return false,
+    };
+
+    parts
+        .try_fold(initial, |current, part| match current {
+            async_graphql::Value::List(list) => {
+                part.parse::<usize>().ok().and_then(|idx| list.get(idx))
+            }
+            async_graphql::Value::Object(obj) => obj.get(part),
+            _ => None,
+        })
+        .is_some()
+}
@@ -0,0 +1,120 @@
+use async_graphql::extensions::apollo_persisted_queries::{
+    ApolloPersistedQueries, LruCacheStorage,
+};
+
+/// Creates the `ApolloPersistedQueries` extension of the schema, caching the `capacity` most
+/// recently used queries in memory.
+///
+/// Requests only carrying the hash of a query in their `persistedQuery` extension are resolved
+/// from the cache, unknown hashes are answered with a `PersistedQueryNotFound` error, so Apollo
+/// clients send the query again along with its hash:
+///
+/// ```ignore
+/// let schema = Schema::build(Query, EmptyMutation, EmptySubscription)
+///     .extension(persisted_queries(1024))
+///     .extension(GraphQLLimits)
+///     .finish();
+///
+/// let app = router().route("/", get(graphql(schema.clone())).post(graphql(schema)));
+/// ```
+#[must_use]
+pub fn persisted_queries(capacity: usize) -> ApolloPersistedQueries<LruCacheStorage> {
+    ApolloPersistedQueries::new(LruCacheStorage::new(capacity))
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::{EmptyMutation, EmptySubscription, Object, Schema};
+    use viz_core::{http, Context, Extract, Response};
+    use viz_utils::{
+        futures::StreamExt,
+        serde::json::{self, json},
+    };
+
+    use super::*;
+    use crate::{graphql, GraphQLHandlerRequest};
+
+    struct Query;
+
+    #[Object]
+    impl Query {
+        async fn value(&self) -> i32 {
+            1
+        }
+    }
+
+    const HASH: &str = "854174ebed716fe24fd6659c30290aecd9bc1d17dc4f47939a1848a1b8ed3c6b";
+
+    async fn body(resp: Response) -> json::Value {
+        let mut body = http::Response::from(resp).into_body();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.next().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        json::from_slice(&bytes).unwrap()
+    }
+
+    async fn send(
+        schema: &Schema<Query, EmptyMutation, EmptySubscription>,
+        req: http::Request<http::Body>,
+    ) -> json::Value {
+        let mut cx = Context::from(req);
+        let req = GraphQLHandlerRequest::extract(&mut cx).await.ok().unwrap();
+        body(graphql(schema.clone())(req).await).await
+    }
+
+    fn get() -> http::Request<http::Body> {
+        let mut req = http::Request::new(http::Body::empty());
+        *req.uri_mut() = format!(
+            "/?extensions=%7B%22persistedQuery%22%3A%7B%22version%22%3A1%2C\
+             %22sha256Hash%22%3A%22{}%22%7D%7D",
+            HASH
+        )
+        .parse()
+        .unwrap();
+        req
+    }
+
+    fn post() -> http::Request<http::Body> {
+        let body = json!({
+            "query": "{ value }",
+            "extensions": { "persistedQuery": { "version": 1, "sha256Hash": HASH } },
+        });
+        let mut req = http::Request::new(http::Body::from(body.to_string()));
+        *req.method_mut() = http::Method::POST;
+        req.headers_mut().insert(
+            http::header::CONTENT_TYPE,
+            http::HeaderValue::from_static("application/json"),
+        );
+        req
+    }
+
+    #[tokio::test]
+    async fn unknown_hashes_are_not_found() {
+        let schema = Schema::build(Query, EmptyMutation, EmptySubscription)
+            .extension(persisted_queries(16))
+            .finish();
+
+        let resp = send(&schema, get()).await;
+        assert_eq!(
+            resp,
+            json!({ "data": null, "errors": [{ "message": "PersistedQueryNotFound" }] })
+        );
+    }
+
+    #[tokio::test]
+    async fn registered_hashes_are_resolved() {
+        let schema = Schema::build(Query, EmptyMutation, EmptySubscription)
+            .extension(persisted_queries(16))
+            .finish();
+
+        assert_eq!(
+            send(&schema, post()).await,
+            json!({ "data": { "value": 1 } })
+        );
+        assert_eq!(
+            send(&schema, get()).await,
+            json!({ "data": { "value": 1 } })
+        );
+    }
+}
@@ -0,0 +1,45 @@
+use async_graphql::http::{playground_source, GraphQLPlaygroundConfig};
+
+use viz_core::Response;
+use viz_utils::futures::future::{self, Ready};
+
+/// Creates a handler serving the GraphQL Playground page for the config.
+///
+/// ```ignore
+/// .route("/", get(playground(GraphQLPlaygroundConfig::new("/graphql"))))
+/// ```
+pub fn playground(
+    config: GraphQLPlaygroundConfig<'_>,
+) -> impl Fn() -> Ready<Response> + Clone + Send + Sync + 'static {
+    let html = playground_source(config);
+    move || future::ready(Response::html(html.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use viz_core::http::{self, header};
+    use viz_utils::futures::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn serves_the_page_as_html() {
+        let handler =
+            playground(GraphQLPlaygroundConfig::new("/graphql").subscription_endpoint("/ws"));
+        let resp = handler().await;
+
+        assert_eq!(resp.status(), 200);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/html"
+        );
+
+        let mut body = http::Response::from(resp).into_body();
+        let mut html = Vec::new();
+        while let Some(chunk) = body.next().await {
+            html.extend_from_slice(&chunk.unwrap());
+        }
+        let html = String::from_utf8(html).unwrap();
+        assert!(html.contains("/graphql") && html.contains("/ws"));
+    }
+}
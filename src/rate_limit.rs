@@ -0,0 +1,108 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Token buckets limiting the rate of operations by name.
+///
+/// Set it with [`GraphQLConfig::rate_limit`](crate::GraphQLConfig::rate_limit), operations over
+/// their limit are rejected by the extractors with `429 Too Many Requests`:
+///
+/// ```ignore
+/// GraphQLConfig::new().rate_limit(
+///     OperationRateLimit::new()
+///         .operation("GenerateReport", 1, Duration::from_secs(60))
+///         .default_limit(100, Duration::from_secs(1)),
+/// )
+/// ```
+///
+/// The operation name is the `operationName` of the request, or the name of the only operation
+/// of the query. Operations without a limit of their own, including unnamed ones, share the
+/// default bucket, they aren't limited without a default limit.
+///
+/// The operation of a request only carrying the hash of a persisted query is unknown to the
+/// extractors, its bucket is charged by the [`GraphQLLimits`](crate::GraphQLLimits) extension
+/// once the schema looked the hash up, so the name of the persisted operation is used. Such
+/// requests aren't limited without the extension.
+///
+/// The buckets are shared by every caller of the routes of the configuration.
+#[derive(Debug, Default)]
+pub struct OperationRateLimit {
+    limits: HashMap<String, Quota>,
+    default: Option<Quota>,
+    buckets: Mutex<HashMap<Option<String>, Bucket>>,
+}
+
+/// The size of a bucket, which is refilled in `period`.
+#[derive(Debug, Clone, Copy)]
+struct Quota {
+    requests: u32,
+    period: Duration,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl OperationRateLimit {
+    /// Creates a rate limit without limits.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits the operation to `requests` per `period`, in bursts of up to `requests`.
+    #[must_use]
+    pub fn operation(mut self, name: impl Into<String>, requests: u32, period: Duration) -> Self {
+        self.limits.insert(name.into(), Quota { requests, period });
+        self
+    }
+
+    /// Limits the operations without a limit of their own to `requests` per `period`, in bursts
+    /// of up to `requests`.
+    #[must_use]
+    pub fn default_limit(self, requests: u32, period: Duration) -> Self {
+        Self {
+            default: Some(Quota { requests, period }),
+            ..self
+        }
+    }
+
+    /// Takes a token from the bucket of the operation, or returns the time until the next one.
+    pub(crate) fn check(&self, operation_name: Option<&str>) -> Result<(), Duration> {
+        let (key, quota) = match operation_name.and_then(|name| self.limits.get_key_value(name)) {
+            Some((name, quota)) => (Some(name), *quota),
+            None => match self.default {
+                Some(quota) => (None, quota),
+                None => return Ok(()),
+            },
+        };
+
+        if quota.requests == 0 {
+            return Err(quota.period);
+        }
+
+        let now = Instant::now();
+        let capacity = f64::from(quota.requests);
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key.cloned()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+
+        let refill = now.duration_since(bucket.updated).as_secs_f64() / quota.period.as_secs_f64();
+        bucket.tokens = (bucket.tokens + refill * capacity).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let missing = (1.0 - bucket.tokens) / capacity;
+            Err(quota.period.mul_f64(missing))
+        }
+    }
+}
@@ -1,7 +1,51 @@
-use std::convert::TryFrom;
+use std::{convert::TryFrom, error::Error as StdError};
 
-use viz_core::{http, Response};
-use viz_utils::serde::json;
+use async_graphql::{CacheControl, ServerError};
+use viz_core::{http, Context, Response};
+use viz_utils::{
+    futures::{
+        stream::{self, BoxStream, Stream},
+        StreamExt,
+    },
+    serde::json::{self, Value},
+    tracing,
+};
+
+#[cfg(feature = "compression")]
+use crate::compression::Encoding;
+use crate::GraphQLConfig;
+
+/// Serializes the JSON responses in place of `serde_json`, e.g. with a faster encoder.
+///
+/// It's implemented for closures:
+///
+/// ```ignore
+/// GraphQLConfig::new().json_serializer(|resp: &BatchResponse| {
+///     simd_json::to_vec(resp).map_err(Into::into)
+/// })
+/// ```
+pub trait JsonSerializer: Send + Sync + 'static {
+    /// Serializes the response to JSON.
+    fn serialize(
+        &self,
+        resp: &async_graphql::BatchResponse,
+    ) -> Result<Vec<u8>, Box<dyn StdError + Send + Sync>>;
+}
+
+impl<F> JsonSerializer for F
+where
+    F: Fn(&async_graphql::BatchResponse) -> Result<Vec<u8>, Box<dyn StdError + Send + Sync>>
+        + Send
+        + Sync
+        + 'static,
+{
+    fn serialize(
+        &self,
+        resp: &async_graphql::BatchResponse,
+    ) -> Result<Vec<u8>, Box<dyn StdError + Send + Sync>> {
+        self(resp)
+    }
+}
 
 /// Responder for a GraphQL response.
 ///
@@ -9,37 +53,457 @@ use viz_utils::serde::json;
 /// works for both.
 pub struct GraphQLResponse(pub async_graphql::BatchResponse);
 
-impl From<async_graphql::Response> for GraphQLResponse {
-    fn from(resp: async_graphql::Response) -> Self {
-        Self(resp.into())
+impl GraphQLResponse {
+    /// Converts to a response with the [`GraphQLConfig`] of the `Context`.
+    ///
+    /// The conversion from `GraphQLResponse` uses the default configuration, and ignores the
+    /// request headers, e.g. `Accept-Encoding`, or `Accept` for CBOR with the `cbor` feature.
+    pub fn into_response(self, cx: &Context) -> Response {
+        let config = GraphQLConfig::get(cx);
+        let resp = match config.request_id.as_ref().and_then(|f| f(cx)) {
+            Some(id) => self.with_request_id(&id),
+            None => self,
+        };
+        resp.respond(&config, Some((cx.method(), cx.headers())))
     }
-}
 
-impl From<async_graphql::BatchResponse> for GraphQLResponse {
-    fn from(resp: async_graphql::BatchResponse) -> Self {
-        Self(resp)
+    /// Merges the id of the request into the `extensions` of every error as `request_id`, so
+    /// users can quote it. Responses without errors are left as is.
+    #[must_use]
+    pub fn with_request_id(mut self, id: &str) -> Self {
+        let resps = match &mut self.0 {
+            async_graphql::BatchResponse::Single(resp) => std::slice::from_mut(resp),
+            async_graphql::BatchResponse::Batch(resps) => resps.as_mut_slice(),
+        };
+        for err in resps.iter_mut().flat_map(|resp| resp.errors.iter_mut()) {
+            err.extensions
+                .get_or_insert_with(Default::default)
+                .set("request_id", id);
+        }
+        self
     }
-}
 
-impl From<GraphQLResponse> for Response {
-    fn from(gr: GraphQLResponse) -> Self {
-        let mut resp = Response::json(Into::<String>::into(json::to_string(&gr.0).unwrap()));
-        if gr.0.is_ok() {
-            if let Some(cache_control) = gr.0.cache_control().value() {
+    /// Returns `true` if no response of the batch has errors.
+    #[must_use]
+    pub fn is_ok(&self) -> bool {
+        self.0.is_ok()
+    }
+
+    /// Gets the number of errors of the responses of the batch.
+    #[must_use]
+    pub fn error_count(&self) -> usize {
+        responses(&self.0)
+            .iter()
+            .map(|resp| resp.errors.len())
+            .sum()
+    }
+
+    /// Gets the cache control of the batch, the most conservative one of its responses, which is
+    /// sent as `Cache-Control` if the batch has no errors.
+    #[must_use]
+    pub fn cache_control(&self) -> CacheControl {
+        cache_control(&self.0)
+    }
+
+    /// Converts to a response, `request` holds the method and headers of the request.
+    pub(crate) fn respond(
+        self,
+        config: &GraphQLConfig,
+        request: Option<(&http::Method, &http::HeaderMap)>,
+    ) -> Response {
+        let (method, headers) = match request {
+            Some((method, headers)) => (Some(method), Some(headers)),
+            None => (None, None),
+        };
+
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "graphql.response",
+            batch = matches!(self.0, async_graphql::BatchResponse::Batch(_)),
+            error_count = self.error_count(),
+            body_size = tracing::field::Empty,
+        )
+        .entered();
+
+        #[cfg(feature = "metrics")]
+        crate::metrics::response(config, self.error_count());
+
+        let (body, content_type) = match encode(&self.0, config, headers) {
+            Ok(encoded) => encoded,
+            Err(err) => {
+                tracing::error!("failed to serialize GraphQL response: {}", err);
+                return (
+                    http::StatusCode::INTERNAL_SERVER_ERROR,
+                    "Internal Server Error",
+                )
+                    .into();
+            }
+        };
+
+        #[cfg(feature = "tracing")]
+        span.record("body_size", body.len());
+
+        let etag = (config.etag && self.0.is_ok()).then(|| etag(&body));
+        let not_modified = matches!(method, Some(&http::Method::GET | &http::Method::HEAD))
+            && etag
+                .as_deref()
+                .zip(headers)
+                .map_or(false, |(etag, headers)| if_none_match(headers, etag));
+        let body = if not_modified { Vec::new() } else { body };
+
+        #[cfg(feature = "compression")]
+        let (body, encoding) = match headers.and_then(Encoding::negotiate) {
+            Some(encoding) if !not_modified && body.len() >= config.min_compress_size => {
+                match encoding.compress(&body) {
+                    Ok(compressed) => (compressed, Some(encoding)),
+                    Err(_) => (body, None),
+                }
+            }
+            _ => (body, None),
+        };
+
+        let content_length = http::HeaderValue::from(body.len());
+        let mut resp = Response::body(body, content_type);
+        resp.headers_mut()
+            .insert(http::header::CONTENT_LENGTH, content_length);
+        #[cfg(feature = "compression")]
+        if let Some(encoding) = encoding {
+            resp.headers_mut().insert(
+                http::header::CONTENT_ENCODING,
+                http::HeaderValue::from_static(encoding.as_str()),
+            );
+        }
+        #[cfg(any(feature = "compression", feature = "cbor"))]
+        if headers.is_some() {
+            // The representation was negotiated, shared caches must not mix them up.
+            let vary = [
+                #[cfg(feature = "cbor")]
+                "accept",
+                #[cfg(feature = "compression")]
+                "accept-encoding",
+            ]
+            .join(", ");
+            if let Ok(value) = http::HeaderValue::from_str(&vary) {
+                resp.headers_mut().append(http::header::VARY, value);
+            }
+        }
+        if config.status_from_errors {
+            if let Some(status) = error_status(&self.0) {
+                *resp.status_mut() = status;
+            }
+        }
+        if self.is_ok() {
+            if let Some(cache_control) = self.cache_control().value() {
                 if let Ok(value) = http::HeaderValue::from_str(&cache_control) {
                     resp.headers_mut()
                         .insert(http::header::CACHE_CONTROL, value);
                 }
             }
         }
-        for (name, value) in gr.0.http_headers() {
+        if let Some(etag) = etag.and_then(|etag| http::HeaderValue::from_str(&etag).ok()) {
+            resp.headers_mut().insert(http::header::ETAG, etag);
+        }
+        if not_modified {
+            *resp.status_mut() = http::StatusCode::NOT_MODIFIED;
+            resp.headers_mut().remove(http::header::CONTENT_TYPE);
+            resp.headers_mut().remove(http::header::CONTENT_LENGTH);
+        }
+        // The headers of the resolvers replace the ones of the response, but their values are
+        // appended to each other, e.g. several `Set-Cookie`. The same value set by several
+        // operations of a batch is kept once.
+        let mut http_headers = http::HeaderMap::new();
+        for (name, value) in self.0.http_headers() {
             if let (Ok(name), Ok(value)) = (
                 http::header::HeaderName::try_from(name.as_bytes()),
                 http::HeaderValue::from_str(value),
             ) {
-                resp.headers_mut().insert(name, value);
+                if !http_headers.get_all(&name).iter().any(|v| *v == value) {
+                    http_headers.append(name, value);
+                }
             }
         }
+        resp.headers_mut().extend(http_headers);
+        if let Some(on_response) = &config.on_response {
+            on_response(&mut resp);
+        }
         resp
     }
 }
+
+impl From<async_graphql::Response> for GraphQLResponse {
+    fn from(resp: async_graphql::Response) -> Self {
+        Self(resp.into())
+    }
+}
+
+impl From<async_graphql::BatchResponse> for GraphQLResponse {
+    fn from(resp: async_graphql::BatchResponse) -> Self {
+        Self(resp)
+    }
+}
+
+/// Converts with the default [`GraphQLConfig`], without the request: the representation isn't
+/// negotiated, and the `ETag`, compression, `Vary`, status from errors, configured headers,
+/// `on_response` callback and request id of the `Context` are all skipped.
+///
+/// Use [`GraphQLResponse::into_response`] to respond with the configuration of the `Context`.
+impl From<GraphQLResponse> for Response {
+    fn from(gr: GraphQLResponse) -> Self {
+        gr.respond(&GraphQLConfig::default(), None)
+    }
+}
+
+/// Converts `async_graphql` responses to responses, without wrapping them in
+/// [`GraphQLResponse`].
+///
+/// `From<async_graphql::Response> for Response` can't be implemented here, both types are
+/// foreign:
+///
+/// ```ignore
+/// async fn handler(cx: &mut Context) -> Result<Response> {
+///     let request = cx.extract::<GraphQLRequest>().await?;
+///     Ok(schema.execute(request.into_inner()).await.into_response(cx))
+/// }
+/// ```
+pub trait IntoGraphQLResponse {
+    /// Converts to a response with the [`GraphQLConfig`] of the `Context`, see
+    /// [`GraphQLResponse::into_response`].
+    fn into_response(self, cx: &Context) -> Response;
+}
+
+impl IntoGraphQLResponse for async_graphql::Response {
+    fn into_response(self, cx: &Context) -> Response {
+        GraphQLResponse::from(self).into_response(cx)
+    }
+}
+
+impl IntoGraphQLResponse for async_graphql::BatchResponse {
+    fn into_response(self, cx: &Context) -> Response {
+        GraphQLResponse::from(self).into_response(cx)
+    }
+}
+
+/// Responder for a stream of GraphQL responses, e.g. from `Schema::execute_stream`.
+///
+/// The responses are written as they are ready in a `multipart/mixed` body, following the
+/// multipart subscription protocol read by Apollo Client: every part carries a whole response
+/// as its `payload`, and the body ends with the closing boundary once the stream ends.
+///
+/// `async_graphql` 2 doesn't implement `@defer` and `@stream`, so there are no incremental
+/// payloads to deliver.
+pub struct GraphQLStreamResponse(pub BoxStream<'static, async_graphql::Response>);
+
+impl GraphQLStreamResponse {
+    /// Creates a responder for the stream of responses.
+    pub fn new(stream: impl Stream<Item = async_graphql::Response> + Send + 'static) -> Self {
+        Self(stream.boxed())
+    }
+}
+
+impl From<GraphQLStreamResponse> for Response {
+    fn from(gr: GraphQLStreamResponse) -> Self {
+        let parts =
+            gr.0.map(|resp| {
+                let payload = json::to_string(&resp)?;
+                Ok(format!(
+                    "\r\n--graphql\r\nContent-Type: application/json\r\n\r\n{{\"payload\":{}}}",
+                    payload
+                ))
+            })
+            .chain(stream::once(async { Ok("\r\n--graphql--\r\n".to_owned()) }))
+            .map(|part: json::Result<String>| {
+                part.map_err(|err| {
+                    tracing::error!("failed to serialize GraphQL response: {}", err);
+                    err
+                })
+            });
+
+        Response::body(
+            http::Body::wrap_stream(parts),
+            r#"multipart/mixed; boundary="graphql"; subscriptionSpec="1.0""#,
+        )
+    }
+}
+
+/// Serializes the response, as CBOR if it's preferred by the `Accept` header.
+#[cfg_attr(not(feature = "cbor"), allow(unused_variables))]
+fn encode(
+    resp: &async_graphql::BatchResponse,
+    config: &GraphQLConfig,
+    headers: Option<&http::HeaderMap>,
+) -> Result<(Vec<u8>, &'static str), Box<dyn StdError + Send + Sync>> {
+    #[cfg(feature = "cbor")]
+    if headers.map_or(false, prefers_cbor) {
+        return Ok((serde_cbor::to_vec(resp)?, "application/cbor"));
+    }
+
+    let body = match &config.json_serializer {
+        Some(serializer) => serializer.serialize(resp)?,
+        None if config.pretty_json => json::to_vec_pretty(resp)?,
+        None => json::to_vec(resp)?,
+    };
+    Ok((body, config.response_content_type))
+}
+
+#[cfg(feature = "cbor")]
+fn prefers_cbor(headers: &http::HeaderMap) -> bool {
+    let mut cbor = 0.0;
+    let mut json = 0.0;
+
+    for value in headers.get_all(http::header::ACCEPT) {
+        for item in value.to_str().unwrap_or_default().split(',') {
+            let mut parts = item.split(';').map(str::trim);
+            let media_type = parts.next().unwrap_or_default();
+            let quality = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())
+                .unwrap_or(0.0);
+
+            if media_type.eq_ignore_ascii_case("application/cbor") {
+                cbor = quality;
+            } else if media_type.eq_ignore_ascii_case("application/json") {
+                json = quality;
+            }
+        }
+    }
+
+    cbor > 0.0 && cbor >= json
+}
+
+/// Creates a weak `ETag` from the length and CRC-32 of the body, before compression.
+///
+/// The checksum is stable across builds, so every instance of a fleet tags a body the same.
+fn etag(body: &[u8]) -> String {
+    format!(r#"W/"{:x}-{:08x}""#, body.len(), crc32fast::hash(body))
+}
+
+/// Checks if the `If-None-Match` header matches the `ETag`, with the weak comparison.
+fn if_none_match(headers: &http::HeaderMap, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+
+    headers
+        .get_all(http::header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+fn responses(resp: &async_graphql::BatchResponse) -> &[async_graphql::Response] {
+    match resp {
+        async_graphql::BatchResponse::Single(resp) => std::slice::from_ref(resp),
+        async_graphql::BatchResponse::Batch(resps) => resps.as_slice(),
+    }
+}
+
+/// Gets the most conservative cache control of the responses.
+///
+/// Unlike `BatchResponse::cache_control`, an operation without a max age makes the whole batch
+/// uncacheable.
+fn cache_control(resp: &async_graphql::BatchResponse) -> CacheControl {
+    responses(resp)
+        .iter()
+        .map(|resp| resp.cache_control)
+        .reduce(|a, b| CacheControl {
+            public: a.public && b.public,
+            max_age: a.max_age.min(b.max_age),
+        })
+        .unwrap_or_default()
+}
+
+/// Gets the HTTP status from the first error of the response.
+fn error_status(resp: &async_graphql::BatchResponse) -> Option<http::StatusCode> {
+    let err = responses(resp)
+        .iter()
+        .flat_map(|resp| &resp.errors)
+        .next()?;
+
+    Some(extension_status(err).unwrap_or(if err.path.is_empty() {
+        http::StatusCode::BAD_REQUEST
+    } else {
+        http::StatusCode::INTERNAL_SERVER_ERROR
+    }))
+}
+
+fn extension_status(err: &ServerError) -> Option<http::StatusCode> {
+    let extensions = json::to_value(err.extensions.as_ref()?).ok()?;
+
+    if let Some(status) = extensions.get("status").and_then(Value::as_u64) {
+        return u16::try_from(status)
+            .ok()
+            .and_then(|status| http::StatusCode::from_u16(status).ok())
+            .filter(|status| status.is_client_error() || status.is_server_error());
+    }
+
+    match extensions.get("code")?.as_str()? {
+        "BAD_USER_INPUT" | "GRAPHQL_PARSE_FAILED" | "GRAPHQL_VALIDATION_FAILED" => {
+            Some(http::StatusCode::BAD_REQUEST)
+        }
+        "UNAUTHENTICATED" => Some(http::StatusCode::UNAUTHORIZED),
+        "FORBIDDEN" => Some(http::StatusCode::FORBIDDEN),
+        "NOT_FOUND" => Some(http::StatusCode::NOT_FOUND),
+        "INTERNAL_SERVER_ERROR" => Some(http::StatusCode::INTERNAL_SERVER_ERROR),
+        "TIMEOUT" => Some(http::StatusCode::GATEWAY_TIMEOUT),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response() -> GraphQLResponse {
+        async_graphql::Response::new(
+            async_graphql::Value::from_json(json::json!({"a": 1})).unwrap(),
+        )
+        .into()
+    }
+
+    #[tokio::test]
+    async fn streams_are_written_as_multipart_subscriptions() {
+        let responses = (1..=2).map(|value| {
+            async_graphql::Response::new(
+                async_graphql::Value::from_json(json::json!({ "value": value })).unwrap(),
+            )
+        });
+        let resp = Response::from(GraphQLStreamResponse::new(stream::iter(responses)));
+        assert_eq!(
+            resp.headers()[http::header::CONTENT_TYPE],
+            r#"multipart/mixed; boundary="graphql"; subscriptionSpec="1.0""#
+        );
+
+        let mut body = http::Response::from(resp).into_body();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.next().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(
+            String::from_utf8(bytes).unwrap(),
+            "\r\n--graphql\r\nContent-Type: application/json\r\n\r\n\
+             {\"payload\":{\"data\":{\"value\":1}}}\
+             \r\n--graphql\r\nContent-Type: application/json\r\n\r\n\
+             {\"payload\":{\"data\":{\"value\":2}}}\
+             \r\n--graphql--\r\n"
+        );
+    }
+
+    #[test]
+    fn etags_are_stable() {
+        assert_eq!(etag(br#"{"data":{"a":1}}"#), r#"W/"10-965bad75""#);
+    }
+
+    #[test]
+    fn matching_etags_are_not_modified() {
+        let config = GraphQLConfig::new().etag(true);
+        let resp = response().respond(&config, Some((&http::Method::GET, &http::HeaderMap::new())));
+        let etag = resp.headers().get(http::header::ETAG).cloned().unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        let mut headers = http::HeaderMap::new();
+        headers.insert(http::header::IF_NONE_MATCH, etag.clone());
+        let resp = response().respond(&config, Some((&http::Method::GET, &headers)));
+        assert_eq!(resp.status(), http::StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers().get(http::header::ETAG), Some(&etag));
+    }
+}
@@ -0,0 +1,171 @@
+use async_graphql::{http::GraphQLPlaygroundConfig, ObjectType, Schema, SubscriptionType};
+
+use viz_core::{ws::Ws, Response};
+use viz_router::{route, Router};
+use viz_utils::futures::future;
+
+use crate::{
+    federation_sdl, graphiql, graphql, graphql_single_flight, graphql_subscription, playground,
+    sdl, SecWebsocketProtocol,
+};
+
+/// A builder of the routes of a schema under a base path.
+///
+/// * `<path>` executes `GET` and `POST` requests with the [`graphql`] handler, or `GET` requests
+///   with the [`graphql_single_flight`] handler if enabled.
+/// * `<path>/ws` serves subscriptions over WebSocket, enabled by default.
+/// * `<path>/graphiql` serves the GraphiQL page.
+/// * `<path>/playground` serves the GraphQL Playground page.
+/// * `<path>/schema.graphql` serves the SDL of the schema, or its federation SDL.
+///
+/// The pages send requests to `<path>`, so it must be the full path of the routes:
+///
+/// ```ignore
+/// let app = router().scope("", GraphQLRoutes::new("/graphql", schema).graphiql(true).router());
+/// ```
+pub struct GraphQLRoutes<Query, Mutation, Subscription> {
+    path: String,
+    schema: Schema<Query, Mutation, Subscription>,
+    subscriptions: bool,
+    graphiql: bool,
+    playground: bool,
+    sdl: bool,
+    federation: bool,
+    single_flight: bool,
+}
+
+impl<Query, Mutation, Subscription> GraphQLRoutes<Query, Mutation, Subscription>
+where
+    Query: ObjectType + 'static,
+    Mutation: ObjectType + 'static,
+    Subscription: SubscriptionType + 'static,
+{
+    /// Creates the routes of the schema under the path.
+    #[must_use]
+    pub fn new(path: &str, schema: Schema<Query, Mutation, Subscription>) -> Self {
+        Self {
+            path: path.trim_end_matches('/').to_owned(),
+            schema,
+            subscriptions: true,
+            graphiql: false,
+            playground: false,
+            sdl: false,
+            federation: false,
+            single_flight: false,
+        }
+    }
+
+    /// Serves subscriptions at `<path>/ws`, enabled by default.
+    #[must_use]
+    pub fn subscriptions(self, enabled: bool) -> Self {
+        Self {
+            subscriptions: enabled,
+            ..self
+        }
+    }
+
+    /// Serves the GraphiQL page at `<path>/graphiql`, disabled by default.
+    #[must_use]
+    pub fn graphiql(self, enabled: bool) -> Self {
+        Self {
+            graphiql: enabled,
+            ..self
+        }
+    }
+
+    /// Serves the GraphQL Playground page at `<path>/playground`, disabled by default.
+    #[must_use]
+    pub fn playground(self, enabled: bool) -> Self {
+        Self {
+            playground: enabled,
+            ..self
+        }
+    }
+
+    /// Serves the SDL of the schema at `<path>/schema.graphql`, disabled by default.
+    #[must_use]
+    pub fn sdl(self, enabled: bool) -> Self {
+        Self {
+            sdl: enabled,
+            ..self
+        }
+    }
+
+    /// Serves the federation SDL of the schema at `<path>/schema.graphql` instead of its SDL, for
+    /// Apollo Federation gateways, disabled by default. The route is enabled by [`Self::sdl`].
+    #[must_use]
+    pub fn federation(self, enabled: bool) -> Self {
+        Self {
+            federation: enabled,
+            ..self
+        }
+    }
+
+    /// Runs identical `GET` queries in flight at the same time only once, with the
+    /// [`graphql_single_flight`] handler, disabled by default.
+    #[must_use]
+    pub fn single_flight(self, enabled: bool) -> Self {
+        Self {
+            single_flight: enabled,
+            ..self
+        }
+    }
+
+    /// Creates the router of the enabled routes.
+    pub fn router(self) -> Router {
+        let endpoint = if self.path.is_empty() {
+            "/".to_owned()
+        } else {
+            self.path.clone()
+        };
+        let subscription_endpoint = self.subscriptions.then(|| format!("{}/ws", self.path));
+
+        let handler = graphql(self.schema.clone());
+        let graphql_route = if self.single_flight {
+            route("")
+                .get(graphql_single_flight(self.schema.clone()))
+                .post(handler)
+        } else {
+            route("").get(handler.clone()).post(handler)
+        };
+        let mut router = Router::new(&self.path).route(graphql_route);
+
+        if self.subscriptions {
+            let schema = self.schema.clone();
+            router = router.route(route("/ws").get(
+                move |ws: Ws, protocol: SecWebsocketProtocol| {
+                    let schema = schema.clone();
+                    let resp = ws.on_upgrade(move |websocket| {
+                        graphql_subscription(websocket, schema, protocol)
+                    });
+                    future::ready(protocol.confirm(resp))
+                },
+            ));
+        }
+
+        if self.graphiql {
+            let endpoint = endpoint.clone();
+            let subscription_endpoint = subscription_endpoint.clone();
+            router = router.route(route("/graphiql").get(move || {
+                future::ready::<Response>(graphiql(&endpoint, subscription_endpoint.as_deref()))
+            }));
+        }
+
+        if self.playground {
+            let config = GraphQLPlaygroundConfig::new(&endpoint);
+            let config = match &subscription_endpoint {
+                Some(subscription_endpoint) => config.subscription_endpoint(subscription_endpoint),
+                None => config,
+            };
+            router = router.route(route("/playground").get(playground(config)));
+        }
+
+        if self.sdl && self.federation {
+            router = router.route(route("/schema.graphql").get(federation_sdl(&self.schema)));
+        } else if self.sdl {
+            router = router.route(route("/schema.graphql").get(sdl(&self.schema)));
+        }
+
+        router
+    }
+}
@@ -0,0 +1,122 @@
+use async_graphql::{ObjectType, Schema, SubscriptionType};
+
+use viz_core::Response;
+use viz_utils::futures::future::{self, Ready};
+
+/// Creates a handler serving the SDL of the schema as `text/plain`.
+///
+/// ```ignore
+/// .route("/schema.graphql", get(sdl(&schema)))
+/// ```
+pub fn sdl<Query, Mutation, Subscription>(
+    schema: &Schema<Query, Mutation, Subscription>,
+) -> impl Fn() -> Ready<Response> + Clone + Send + Sync + 'static
+where
+    Query: ObjectType + 'static,
+    Mutation: ObjectType + 'static,
+    Subscription: SubscriptionType + 'static,
+{
+    text(schema.sdl())
+}
+
+/// Creates a handler serving the federation SDL of the schema as `text/plain`, as expected by
+/// Apollo Federation tooling.
+///
+/// The `_service` and `_entities` fields are resolved by the schema, once federation is enabled
+/// or an entity is declared. The `representations` of `_entities` are plain variables, so the
+/// [`graphql`](crate::graphql) handler passes them through as is:
+///
+/// ```ignore
+/// let schema = Schema::build(Query, EmptyMutation, EmptySubscription)
+///     .enable_federation()
+///     .finish();
+///
+/// let app = router()
+///     .route("/", post(graphql(schema.clone())))
+///     .route("/schema.graphql", get(federation_sdl(&schema)));
+/// ```
+pub fn federation_sdl<Query, Mutation, Subscription>(
+    schema: &Schema<Query, Mutation, Subscription>,
+) -> impl Fn() -> Ready<Response> + Clone + Send + Sync + 'static
+where
+    Query: ObjectType + 'static,
+    Mutation: ObjectType + 'static,
+    Subscription: SubscriptionType + 'static,
+{
+    text(schema.federation_sdl())
+}
+
+fn text(sdl: String) -> impl Fn() -> Ready<Response> + Clone + Send + Sync + 'static {
+    move || future::ready(Response::text(sdl.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::{EmptyMutation, EmptySubscription, Object, SimpleObject, ID};
+    use viz_core::{http, Context, Extract};
+    use viz_utils::{
+        futures::StreamExt,
+        serde::json::{self, json},
+    };
+
+    use super::*;
+    use crate::{graphql, GraphQLHandlerRequest};
+
+    #[derive(SimpleObject)]
+    struct User {
+        id: ID,
+        name: String,
+    }
+
+    struct Query;
+
+    #[Object]
+    impl Query {
+        #[graphql(entity)]
+        async fn find_user_by_id(&self, id: ID) -> User {
+            let name = format!("user {}", id.as_str());
+            User { id, name }
+        }
+    }
+
+    #[tokio::test]
+    async fn entities_are_resolved_from_their_representations() {
+        let schema = Schema::new(Query, EmptyMutation, EmptySubscription);
+        let body = json!({
+            "query": "query($representations: [_Any!]!) { \
+                _entities(representations: $representations) { ... on User { id name } } }",
+            "variables": { "representations": [{ "__typename": "User", "id": "1" }] },
+        });
+        let mut req = http::Request::new(http::Body::from(body.to_string()));
+        *req.method_mut() = http::Method::POST;
+        req.headers_mut().insert(
+            http::header::CONTENT_TYPE,
+            http::HeaderValue::from_static("application/json"),
+        );
+        let mut cx = Context::from(req);
+
+        let req = GraphQLHandlerRequest::extract(&mut cx).await.ok().unwrap();
+        let mut body = http::Response::from(graphql(schema)(req).await).into_body();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.next().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(
+            json::from_slice::<json::Value>(&bytes).unwrap(),
+            json!({ "data": { "_entities": [{ "id": "1", "name": "user 1" }] } })
+        );
+    }
+
+    #[tokio::test]
+    async fn federation_sdl_declares_the_entities() {
+        let schema = Schema::new(Query, EmptyMutation, EmptySubscription);
+        let mut body = http::Response::from(federation_sdl(&schema)().await).into_body();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.next().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        assert!(String::from_utf8(bytes)
+            .unwrap()
+            .contains(r#"type User @key(fields: "id")"#));
+    }
+}
@@ -0,0 +1,520 @@
+//! Server-Sent Events transport for subscriptions, following the
+//! [`graphql-sse`](https://github.com/enisdenjo/graphql-sse/blob/master/PROTOCOL.md) protocol.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_graphql::{parser::types::OperationType, ObjectType, Schema, SubscriptionType};
+use tokio::{
+    sync::mpsc::{self, Receiver, Sender},
+    task::JoinHandle,
+};
+
+use viz_core::{
+    http,
+    sse::{self, Event},
+    Context, Extract, Response,
+};
+use viz_utils::{
+    futures::{
+        stream::{self, Stream},
+        StreamExt,
+    },
+    serde::json::{self, json},
+};
+
+use crate::{rejection, GraphQLBatchRequest, GraphQLRequest};
+
+/// The header carrying the stream token of the single connection mode.
+pub const STREAM_TOKEN_HEADER: &str = "x-graphql-event-stream-token";
+
+/// GraphQL subscription handler over Server-Sent Events, in the distinct connections mode.
+///
+/// Each `async_graphql::Response` is sent as a `next` event, followed by a `complete` event.
+pub fn graphql_sse<Query, Mutation, Subscription>(
+    schema: Schema<Query, Mutation, Subscription>,
+    request: GraphQLRequest,
+) -> Response
+where
+    Query: ObjectType + 'static,
+    Mutation: ObjectType + 'static,
+    Subscription: SubscriptionType + 'static,
+{
+    GraphQLSse::new(schema).response(request)
+}
+
+/// A configurable GraphQL subscription handler over Server-Sent Events.
+///
+/// [`GraphQLSse::handle`] serves both modes of the protocol, it must be shared by the routes
+/// of the single connection mode, which keeps the reserved streams:
+///
+/// ```ignore
+/// let sse = GraphQLSse::new(schema).keep_alive(Some(Duration::from_secs(10)));
+///
+/// async move |cx: &mut Context| Ok(sse.handle(cx).await)
+/// ```
+///
+/// Reservations which aren't opened in time are released, and their number is capped, so
+/// reserving streams without opening them can't exhaust the memory.
+pub struct GraphQLSse<Query, Mutation, Subscription> {
+    schema: Schema<Query, Mutation, Subscription>,
+    keep_alive: Option<Duration>,
+    reservation_ttl: Duration,
+    max_reservations: usize,
+    stream_buffer: usize,
+    streams: Arc<Mutex<HashMap<String, Reservation>>>,
+}
+
+impl<Query, Mutation, Subscription> Clone for GraphQLSse<Query, Mutation, Subscription> {
+    fn clone(&self) -> Self {
+        Self {
+            schema: self.schema.clone(),
+            keep_alive: self.keep_alive,
+            reservation_ttl: self.reservation_ttl,
+            max_reservations: self.max_reservations,
+            stream_buffer: self.stream_buffer,
+            streams: self.streams.clone(),
+        }
+    }
+}
+
+impl<Query, Mutation, Subscription> GraphQLSse<Query, Mutation, Subscription>
+where
+    Query: ObjectType + 'static,
+    Mutation: ObjectType + 'static,
+    Subscription: SubscriptionType + 'static,
+{
+    /// Interval of the heartbeat comments, defaults to 15 seconds.
+    pub const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+    /// Time for a reserved stream to be opened, defaults to 30 seconds.
+    pub const DEFAULT_RESERVATION_TTL: Duration = Duration::from_secs(30);
+
+    /// Max number of reserved streams, opened or not, defaults to 1024.
+    pub const DEFAULT_MAX_RESERVATIONS: usize = 1024;
+
+    /// Max number of events buffered by a reserved stream, defaults to 64.
+    pub const DEFAULT_STREAM_BUFFER: usize = 64;
+
+    /// Creates a Server-Sent Events handler for the schema.
+    pub fn new(schema: Schema<Query, Mutation, Subscription>) -> Self {
+        Self {
+            schema,
+            keep_alive: Some(Self::DEFAULT_KEEP_ALIVE),
+            reservation_ttl: Self::DEFAULT_RESERVATION_TTL,
+            max_reservations: Self::DEFAULT_MAX_RESERVATIONS,
+            stream_buffer: Self::DEFAULT_STREAM_BUFFER,
+            streams: Arc::default(),
+        }
+    }
+
+    /// Sets the interval of the heartbeat comments sent when no event was sent, `None` disables
+    /// them.
+    #[must_use]
+    pub fn keep_alive(self, interval: Option<Duration>) -> Self {
+        Self {
+            keep_alive: interval,
+            ..self
+        }
+    }
+
+    /// Sets the time for a reserved stream to be opened, it's released with its operations
+    /// afterwards.
+    #[must_use]
+    pub fn reservation_ttl(self, ttl: Duration) -> Self {
+        Self {
+            reservation_ttl: ttl,
+            ..self
+        }
+    }
+
+    /// Sets the maximum number of reserved streams, opened or not, further `PUT` requests are
+    /// answered with `503 Service Unavailable`.
+    #[must_use]
+    pub fn max_reservations(self, max: usize) -> Self {
+        Self {
+            max_reservations: max,
+            ..self
+        }
+    }
+
+    /// Sets the maximum number of events buffered by a reserved stream, the operations of the
+    /// stream wait for the buffer to be read once it's full, e.g. before the stream is opened.
+    #[must_use]
+    pub fn stream_buffer(self, size: usize) -> Self {
+        Self {
+            stream_buffer: size,
+            ..self
+        }
+    }
+
+    /// Executes the request and streams the responses, in the distinct connections mode.
+    pub fn response(&self, request: GraphQLRequest) -> Response {
+        let events = self
+            .schema
+            .execute_stream(request.into_inner())
+            .map(|resp| next_event(None, &resp))
+            .chain(stream::once(async { Ok(complete_event(None)) }));
+
+        reply(events, self.keep_alive)
+    }
+
+    /// Handles a request of either mode.
+    ///
+    /// Requests with a stream token, from the `X-GraphQL-Event-Stream-Token` header or the
+    /// `token` parameter, use the single connection mode:
+    ///
+    /// * `PUT` reserves a stream and responds with its token.
+    /// * `GET` opens the reserved stream.
+    /// * `POST` executes an operation on the stream, identified by `extensions.operationId`.
+    /// * `DELETE` stops the operation of the `operationId` parameter.
+    ///
+    /// Other `GET` and `POST` requests use the distinct connections mode.
+    pub async fn handle(&self, cx: &mut Context) -> Response {
+        let params = cx.query::<HashMap<String, String>>().unwrap_or_default();
+        let token = cx
+            .header::<String>(STREAM_TOKEN_HEADER)
+            .or_else(|| params.get("token").cloned());
+
+        match (cx.method().clone(), token) {
+            (http::Method::PUT, _) => self.reserve(),
+            (http::Method::GET, Some(token)) => self.open(&token),
+            (http::Method::POST, Some(token)) => match GraphQLRequest::extract(cx).await {
+                Ok(request) => self.execute(&token, request),
+                Err(rejection) => rejection.into_response(cx),
+            },
+            (http::Method::DELETE, Some(token)) => match params.get("operationId") {
+                Some(id) => self.stop(&token, id),
+                None => http::StatusCode::BAD_REQUEST.into(),
+            },
+            (http::Method::GET, None) => {
+                // Subscriptions are the point of the transport, they are allowed over `GET`.
+                let allowed = &[OperationType::Query, OperationType::Subscription];
+                match GraphQLBatchRequest::extract_allowing_over_get(cx, allowed).await {
+                    Ok(batch) => match batch.into_inner().into_single() {
+                        Ok(request) => self.response(GraphQLRequest(request)),
+                        Err(err) => rejection::GraphQLRejection::from(err).into_response(cx),
+                    },
+                    Err(rejection) => rejection.into_response(cx),
+                }
+            }
+            (http::Method::POST, None) => match GraphQLRequest::extract(cx).await {
+                Ok(request) => self.response(request),
+                Err(rejection) => rejection.into_response(cx),
+            },
+            _ => http::StatusCode::METHOD_NOT_ALLOWED.into(),
+        }
+    }
+
+    /// Reserves a stream of the single connection mode, released if it isn't opened in time.
+    fn reserve(&self) -> Response {
+        let token = match new_token() {
+            Ok(token) => token,
+            Err(_) => return http::StatusCode::INTERNAL_SERVER_ERROR.into(),
+        };
+        let (sender, receiver) = mpsc::channel(self.stream_buffer.max(1));
+
+        {
+            let mut streams = self.streams.lock().unwrap();
+            if streams.len() >= self.max_reservations {
+                return http::StatusCode::SERVICE_UNAVAILABLE.into();
+            }
+            streams.insert(
+                token.clone(),
+                Reservation {
+                    sender,
+                    receiver: Some(receiver),
+                    operations: HashMap::new(),
+                },
+            );
+        }
+
+        let ttl = self.reservation_ttl;
+        let streams = self.streams.clone();
+        let expired = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(ttl).await;
+            let mut streams = streams.lock().unwrap();
+            if streams
+                .get(&expired)
+                .map_or(false, |reservation| reservation.receiver.is_some())
+            {
+                if let Some(reservation) = streams.remove(&expired) {
+                    reservation.operations.values().for_each(JoinHandle::abort);
+                }
+            }
+        });
+
+        (http::StatusCode::CREATED, token).into()
+    }
+
+    /// Opens a reserved stream, the reservation is released when the stream is closed.
+    fn open(&self, token: &str) -> Response {
+        let mut receiver = match self.streams.lock().unwrap().get_mut(token) {
+            Some(reservation) => match reservation.receiver.take() {
+                Some(receiver) => receiver,
+                None => return http::StatusCode::CONFLICT.into(),
+            },
+            None => return http::StatusCode::NOT_FOUND.into(),
+        };
+
+        let guard = StreamGuard {
+            token: token.to_owned(),
+            streams: self.streams.clone(),
+        };
+        let events = stream::poll_fn(move |cx| {
+            let _guard = &guard;
+            receiver.poll_recv(cx)
+        });
+
+        reply(events.map(Ok::<_, Infallible>), self.keep_alive)
+    }
+
+    /// Executes an operation on a reserved stream.
+    fn execute(&self, token: &str, request: GraphQLRequest) -> Response {
+        let request = request.into_inner();
+        let id = match request.extensions.get("operationId") {
+            Some(async_graphql::Value::String(id)) => id.clone(),
+            _ => return http::StatusCode::BAD_REQUEST.into(),
+        };
+
+        let mut streams = self.streams.lock().unwrap();
+        let reservation = match streams.get_mut(token) {
+            Some(reservation) => reservation,
+            None => return http::StatusCode::NOT_FOUND.into(),
+        };
+        if reservation.operations.contains_key(&id) {
+            return http::StatusCode::CONFLICT.into();
+        }
+
+        let mut responses = self.schema.execute_stream(request);
+        let sender = reservation.sender.clone();
+        let streams = self.streams.clone();
+        let (token, operation_id) = (token.to_owned(), id.clone());
+
+        let handle = tokio::spawn(async move {
+            while let Some(resp) = responses.next().await {
+                let event = match next_event(Some(&operation_id), &resp) {
+                    Ok(event) => event,
+                    Err(_) => break,
+                };
+                if sender.send(event).await.is_err() {
+                    return;
+                }
+            }
+            let _ = sender.send(complete_event(Some(&operation_id))).await;
+
+            if let Some(reservation) = streams.lock().unwrap().get_mut(&token) {
+                reservation.operations.remove(&operation_id);
+            }
+        });
+        reservation.operations.insert(id, handle);
+
+        http::StatusCode::ACCEPTED.into()
+    }
+
+    /// Stops an operation of a reserved stream.
+    fn stop(&self, token: &str, id: &str) -> Response {
+        match self.streams.lock().unwrap().get_mut(token) {
+            Some(reservation) => {
+                if let Some(handle) = reservation.operations.remove(id) {
+                    handle.abort();
+                }
+                http::StatusCode::OK.into()
+            }
+            None => http::StatusCode::NOT_FOUND.into(),
+        }
+    }
+}
+
+/// A reserved stream of the single connection mode.
+struct Reservation {
+    sender: Sender<Event>,
+    receiver: Option<Receiver<Event>>,
+    operations: HashMap<String, JoinHandle<()>>,
+}
+
+/// Releases the reservation and stops its operations when the stream is closed.
+struct StreamGuard {
+    token: String,
+    streams: Arc<Mutex<HashMap<String, Reservation>>>,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        if let Ok(mut streams) = self.streams.lock() {
+            if let Some(reservation) = streams.remove(&self.token) {
+                reservation.operations.values().for_each(JoinHandle::abort);
+            }
+        }
+    }
+}
+
+fn next_event(id: Option<&str>, resp: &async_graphql::Response) -> json::Result<Event> {
+    let data = match id {
+        Some(id) => format!(
+            r#"{{"id":{},"payload":{}}}"#,
+            json::to_string(id)?,
+            json::to_string(resp)?
+        ),
+        None => json::to_string(resp)?,
+    };
+    Ok(Event::default().event("next").data(data))
+}
+
+fn complete_event(id: Option<&str>) -> Event {
+    let data = match id {
+        Some(id) => json!({ "id": id }).to_string(),
+        None => String::new(),
+    };
+    Event::default().event("complete").data(data)
+}
+
+fn reply<S, E>(events: S, keep_alive: Option<Duration>) -> Response
+where
+    S: Stream<Item = Result<Event, E>> + Send + 'static,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    match keep_alive {
+        Some(interval) => sse::reply(sse::keep_alive().interval(interval).stream(events)),
+        None => sse::reply(events),
+    }
+}
+
+/// Creates an unguessable stream token, from 128 bits of the random number generator of the OS.
+fn new_token() -> Result<String, getrandom::Error> {
+    let mut bytes = [0; 16];
+    getrandom::getrandom(&mut bytes)?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::{EmptyMutation, Object, Subscription};
+    use viz_utils::futures::stream;
+
+    use super::*;
+    use crate::GraphQLConfig;
+
+    struct Query;
+
+    #[Object]
+    impl Query {
+        async fn value(&self) -> i32 {
+            1
+        }
+    }
+
+    struct Subscription;
+
+    #[Subscription]
+    impl Subscription {
+        async fn values(&self) -> impl Stream<Item = i32> {
+            stream::iter(0..2)
+        }
+    }
+
+    type Sse = GraphQLSse<Query, EmptyMutation, Subscription>;
+
+    fn sse() -> Sse {
+        GraphQLSse::new(Schema::new(Query, EmptyMutation, Subscription))
+    }
+
+    fn context(method: http::Method, uri: &str) -> Context {
+        let mut req = http::Request::new(http::Body::empty());
+        *req.method_mut() = method;
+        *req.uri_mut() = uri.parse().unwrap();
+        Context::from(req)
+    }
+
+    async fn reserve(sse: &Sse) -> (http::StatusCode, String) {
+        let resp = http::Response::from(sse.handle(&mut context(http::Method::PUT, "/")).await);
+        let (parts, mut body) = resp.into_parts();
+        let mut token = Vec::new();
+        while let Some(chunk) = body.next().await {
+            token.extend_from_slice(&chunk.unwrap());
+        }
+        (parts.status, String::from_utf8(token).unwrap())
+    }
+
+    #[tokio::test]
+    async fn unopened_reservations_expire() {
+        let sse = sse().reservation_ttl(Duration::from_millis(20));
+        let (status, token) = reserve(&sse).await;
+        assert_eq!(status, http::StatusCode::CREATED);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let uri = format!("/?token={}", token);
+        let resp = sse.handle(&mut context(http::Method::GET, &uri)).await;
+        assert_eq!(resp.status(), http::StatusCode::NOT_FOUND);
+        assert!(sse.streams.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn opened_reservations_outlive_the_ttl() {
+        let sse = sse().reservation_ttl(Duration::from_millis(20));
+        let (_, token) = reserve(&sse).await;
+
+        let uri = format!("/?token={}", token);
+        let resp = sse.handle(&mut context(http::Method::GET, &uri)).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(sse.streams.lock().unwrap().contains_key(&token));
+
+        drop(resp);
+        assert!(sse.streams.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn get_allows_subscriptions() {
+        let mut cx = context(http::Method::GET, "/?query=subscription%7Bvalues%7D");
+        let resp = sse().handle(&mut cx).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn get_rejects_mutations() {
+        let mut cx = context(http::Method::GET, "/?query=mutation%7Bvalue%7D");
+        let resp = sse().handle(&mut cx).await;
+        assert_eq!(resp.status(), http::StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn get_allows_mutations_once_enabled() {
+        let mut cx = context(http::Method::GET, "/?query=mutation%7Bvalue%7D");
+        cx.extensions_mut()
+            .insert(GraphQLConfig::new().reject_mutations_over_get(false));
+        let resp = sse().handle(&mut cx).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn get_applies_the_config() {
+        let mut cx = context(http::Method::GET, "/?query=subscription%7Bvalues%7D");
+        cx.extensions_mut()
+            .insert(GraphQLConfig::new().persisted_queries_only(true));
+        let resp = sse().handle(&mut cx).await;
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn tokens_are_random() {
+        let (a, b) = (new_token().unwrap(), new_token().unwrap());
+        assert_eq!(a.len(), 32);
+        assert!(a.bytes().all(|byte| byte.is_ascii_hexdigit()));
+        assert_ne!(a, b);
+    }
+
+    #[tokio::test]
+    async fn reservations_are_capped() {
+        let sse = sse().max_reservations(1);
+        assert_eq!(reserve(&sse).await.0, http::StatusCode::CREATED);
+        assert_eq!(reserve(&sse).await.0, http::StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
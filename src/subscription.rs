@@ -1,23 +1,54 @@
-use std::{borrow::Cow, future::Future};
+use std::{
+    borrow::Cow,
+    collections::{HashSet, VecDeque},
+    fmt,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
 use async_graphql::{
-    http::{WebSocketProtocols, WsMessage},
-    Data, ObjectType, Result, Schema, SubscriptionType,
+    http::{ClientMessage, WebSocketProtocols, WsMessage},
+    Data, Error, ObjectType, Result, Schema, SubscriptionType,
+};
+use tokio::{
+    sync::{mpsc, Notify},
+    time,
 };
 
 use viz_core::{
     http::{
         header,
-        headers::{self, Header, HeaderName, HeaderValue},
+        headers::{self, Header, HeaderMapExt, HeaderName, HeaderValue},
+        HeaderMap, StatusCode,
     },
+    types::Cookies,
     ws::{Message, WebSocket},
+    Context, Extract, Response,
 };
 use viz_utils::{
-    futures::{future, SinkExt, StreamExt},
-    serde::json::Value,
+    futures::{
+        future::{self, BoxFuture},
+        pin_mut,
+        stream::SplitSink,
+        FutureExt, SinkExt, StreamExt,
+    },
+    serde::json::{self, json, Value},
 };
 
+use crate::{GraphQLConfig, GraphQLData};
+
 /// The Sec-Websocket-Protocol header.
+///
+/// It's decoded to the first protocol offered by the client which is supported, and encoded to
+/// the selected protocol. Offering no supported protocol is invalid, rather than assuming one the
+/// client doesn't speak.
+///
+/// As an extractor, it rejects unsupported protocols with `400 Bad Request`, and a missing header
+/// too, unless a default protocol is set by [`GraphQLConfig::default_websocket_protocol`].
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub struct SecWebsocketProtocol(pub WebSocketProtocols);
 
@@ -31,17 +62,26 @@ impl Header for SecWebsocketProtocol {
         Self: Sized,
         I: Iterator<Item = &'i HeaderValue>,
     {
-        match values.next() {
-            Some(value) => Ok(SecWebsocketProtocol(
+        let mut values = values.peekable();
+        if values.peek().is_none() {
+            return Err(headers::Error::invalid());
+        }
+
+        // The offered protocols may be listed in one or several values, the first supported
+        // one is selected.
+        let mut protocol = None;
+        for value in values {
+            let value = value.to_str().map_err(|_| headers::Error::invalid())?;
+            protocol = protocol.or_else(|| {
                 value
-                    .to_str()
-                    .map_err(|_| headers::Error::invalid())?
-                    .parse()
-                    .ok()
-                    .unwrap_or(WebSocketProtocols::SubscriptionsTransportWS),
-            )),
-            None => Err(headers::Error::invalid()),
+                    .split(',')
+                    .find_map(|protocol| protocol.trim().parse::<WebSocketProtocols>().ok())
+            });
         }
+
+        protocol
+            .map(SecWebsocketProtocol)
+            .ok_or_else(headers::Error::invalid)
     }
 
     fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
@@ -51,7 +91,45 @@ impl Header for SecWebsocketProtocol {
     }
 }
 
+impl Extract for SecWebsocketProtocol {
+    type Error = Response;
+
+    fn extract(cx: &mut Context) -> BoxFuture<'_, Result<Self, Self::Error>> {
+        Box::pin(async move {
+            match cx.headers().typed_try_get::<Self>() {
+                Ok(Some(protocol)) => Ok(protocol),
+                Ok(None) => match GraphQLConfig::get(cx).default_websocket_protocol {
+                    Some(protocol) => Ok(SecWebsocketProtocol(protocol)),
+                    None => Err((StatusCode::BAD_REQUEST, "missing websocket subprotocol").into()),
+                },
+                Err(_) => {
+                    Err((StatusCode::BAD_REQUEST, "unsupported websocket subprotocol").into())
+                }
+            }
+        })
+    }
+}
+
+impl SecWebsocketProtocol {
+    /// Confirms the selected protocol on the `101 Switching Protocols` response of the upgrade,
+    /// some clients abort the connection otherwise.
+    ///
+    /// ```ignore
+    /// let resp = ws.on_upgrade(move |websocket| graphql_subscription(websocket, schema, protocol));
+    /// protocol.confirm(resp)
+    /// ```
+    pub fn confirm(&self, mut resp: Response) -> Response {
+        resp.headers_mut().typed_insert(*self);
+        resp
+    }
+}
+
 /// GraphQL subscription handler
+///
+/// Both the `graphql-transport-ws` protocol of `graphql-ws` and the legacy `graphql-ws`
+/// protocol of `subscriptions-transport-ws` are supported, selected by the
+/// [`SecWebsocketProtocol`] of the upgrade request, which is confirmed on the upgrade response
+/// by [`SecWebsocketProtocol::confirm`].
 pub async fn graphql_subscription<Query, Mutation, Subscription>(
     websocket: WebSocket,
     schema: Schema<Query, Mutation, Subscription>,
@@ -82,22 +160,1181 @@ pub async fn graphql_subscription_with_data<Query, Mutation, Subscription, F, R>
     F: FnOnce(Value) -> R + Send + 'static,
     R: Future<Output = Result<Data>> + Send + 'static,
 {
-    let (mut sink, stream) = websocket.split();
-    let input = stream
-        .take_while(|res| future::ready(res.is_ok()))
-        .map(Result::unwrap)
-        .filter(|msg| future::ready(msg.is_text() || msg.is_binary()))
-        .map(Message::into_bytes);
-
-    let mut stream =
-        async_graphql::http::WebSocket::with_data(schema, input, initializer, protocol.0).map(
-            |msg| match msg {
-                WsMessage::Text(text) => Message::text(text),
-                WsMessage::Close(code, status) => Message::close_with(code, Cow::from(status)),
-            },
-        );
-
-    while let Some(item) = stream.next().await {
-        let _ = sink.send(item).await;
+    GraphQLSubscription::new(websocket, schema, protocol)
+        .on_connection_init(initializer)
+        .serve()
+        .await
+}
+
+/// GraphQL subscription handler
+///
+/// Specifies that a function converts the headers of the upgrade request and the init payload
+/// to data, e.g. to authenticate from the `Authorization` header or a session cookie.
+pub async fn graphql_subscription_with_headers<Query, Mutation, Subscription, F, R>(
+    websocket: WebSocket,
+    schema: Schema<Query, Mutation, Subscription>,
+    protocol: SecWebsocketProtocol,
+    headers: HeaderMap,
+    initializer: F,
+) where
+    Query: ObjectType + 'static,
+    Mutation: ObjectType + 'static,
+    Subscription: SubscriptionType + 'static,
+    F: FnOnce(HeaderMap, Value) -> R + Send + 'static,
+    R: Future<Output = Result<Data>> + Send + 'static,
+{
+    GraphQLSubscription::new(websocket, schema, protocol)
+        .on_connection_init_with_headers(headers, initializer)
+        .serve()
+        .await
+}
+
+/// GraphQL subscription handler
+///
+/// Specifies that a function converts the cookies of the upgrade request and the init payload
+/// to data, e.g. for browsers authenticated by an HTTP-only session cookie.
+pub async fn graphql_subscription_with_cookies<Query, Mutation, Subscription, F, R>(
+    websocket: WebSocket,
+    schema: Schema<Query, Mutation, Subscription>,
+    protocol: SecWebsocketProtocol,
+    cookies: Cookies,
+    initializer: F,
+) where
+    Query: ObjectType + 'static,
+    Mutation: ObjectType + 'static,
+    Subscription: SubscriptionType + 'static,
+    F: FnOnce(Cookies, Value) -> R + Send + 'static,
+    R: Future<Output = Result<Data>> + Send + 'static,
+{
+    GraphQLSubscription::new(websocket, schema, protocol)
+        .on_connection_init_with_cookies(cookies, initializer)
+        .serve()
+        .await
+}
+
+/// GraphQL subscription handler
+///
+/// Specifies that a function converts the init payload to data, and a future which closes the
+/// connection when it resolves, e.g. on server shutdown.
+pub async fn graphql_subscription_with_shutdown<Query, Mutation, Subscription, F, R, S>(
+    websocket: WebSocket,
+    schema: Schema<Query, Mutation, Subscription>,
+    protocol: SecWebsocketProtocol,
+    initializer: F,
+    shutdown: S,
+) where
+    Query: ObjectType + 'static,
+    Mutation: ObjectType + 'static,
+    Subscription: SubscriptionType + 'static,
+    F: FnOnce(Value) -> R + Send + 'static,
+    R: Future<Output = Result<Data>> + Send + 'static,
+    S: Future<Output = ()> + Send + 'static,
+{
+    GraphQLSubscription::new(websocket, schema, protocol)
+        .on_connection_init(initializer)
+        .shutdown(shutdown)
+        .serve()
+        .await
+}
+
+type Initializer =
+    Box<dyn FnOnce(Value) -> BoxFuture<'static, Result<(Data, Option<Value>)>> + Send>;
+type InitErrorMapper = Box<dyn FnOnce(Error) -> (u16, String) + Send>;
+type CloseStatusMapper = Arc<dyn Fn(u16, &str) -> String + Send + Sync>;
+type ErrorObserver = Arc<dyn Fn(&SubscriptionError) + Send + Sync>;
+type PingHandler = Arc<dyn Fn(Option<Value>) -> Option<Value> + Send + Sync>;
+type FatalErrorMapper = Arc<dyn Fn(&Value) -> Option<u16> + Send + Sync>;
+
+/// A configurable GraphQL subscription handler.
+///
+/// ```ignore
+/// let resp = ws.on_upgrade(move |websocket| {
+///     GraphQLSubscription::new(websocket, schema, protocol)
+///         .init_timeout(Some(Duration::from_secs(3)))
+///         .serve()
+/// });
+/// protocol.confirm(resp)
+/// ```
+pub struct GraphQLSubscription<Query, Mutation, Subscription> {
+    websocket: WebSocket,
+    schema: Schema<Query, Mutation, Subscription>,
+    protocol: SecWebsocketProtocol,
+    initializer: Initializer,
+    init_timeout: Option<Duration>,
+    keepalive: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    outbound_buffer: Option<(usize, OverflowPolicy)>,
+    max_subscriptions: usize,
+    shutdown: Option<BoxFuture<'static, ()>>,
+    on_init_error: Option<InitErrorMapper>,
+    close_status: Option<CloseStatusMapper>,
+    on_error: Option<ErrorObserver>,
+    on_ping: Option<PingHandler>,
+    close_on_error: Option<FatalErrorMapper>,
+    data: GraphQLData,
+}
+
+impl<Query, Mutation, Subscription> GraphQLSubscription<Query, Mutation, Subscription>
+where
+    Query: ObjectType + 'static,
+    Mutation: ObjectType + 'static,
+    Subscription: SubscriptionType + 'static,
+{
+    /// Max time to wait for the `connection_init` message, defaults to 10 seconds.
+    pub const DEFAULT_INIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Max number of active subscriptions per connection, defaults to 100.
+    pub const DEFAULT_MAX_SUBSCRIPTIONS: usize = 100;
+
+    /// Creates a subscription handler for the WebSocket connection.
+    pub fn new(
+        websocket: WebSocket,
+        schema: Schema<Query, Mutation, Subscription>,
+        protocol: SecWebsocketProtocol,
+    ) -> Self {
+        Self {
+            websocket,
+            schema,
+            protocol,
+            initializer: Box::new(|_| Box::pin(async { Ok((Default::default(), None)) })),
+            init_timeout: Some(Self::DEFAULT_INIT_TIMEOUT),
+            keepalive: None,
+            idle_timeout: None,
+            outbound_buffer: None,
+            max_subscriptions: Self::DEFAULT_MAX_SUBSCRIPTIONS,
+            shutdown: None,
+            on_init_error: None,
+            close_status: None,
+            on_error: None,
+            on_ping: None,
+            close_on_error: None,
+            data: GraphQLData::new(),
+        }
+    }
+
+    /// Specifies that a function converts the init payload to data.
+    #[must_use]
+    pub fn on_connection_init<F, R>(self, initializer: F) -> Self
+    where
+        F: FnOnce(Value) -> R + Send + 'static,
+        R: Future<Output = Result<Data>> + Send + 'static,
+    {
+        self.on_connection_init_with_ack(move |payload| {
+            initializer(payload).map(|res| res.map(|data| (data, None)))
+        })
+    }
+
+    /// Specifies that a function converts the init payload to data, and the payload of the
+    /// `connection_ack` message, e.g. feature flags.
+    ///
+    /// The `connection_ack` message has no payload when it's `None`.
+    #[must_use]
+    pub fn on_connection_init_with_ack<F, R>(self, initializer: F) -> Self
+    where
+        F: FnOnce(Value) -> R + Send + 'static,
+        R: Future<Output = Result<(Data, Option<Value>)>> + Send + 'static,
+    {
+        Self {
+            initializer: Box::new(move |payload| Box::pin(initializer(payload))),
+            ..self
+        }
+    }
+
+    /// Specifies that a function converts the headers of the upgrade request and the init
+    /// payload to data.
+    ///
+    /// The headers must be taken from the `Context` before upgrading, e.g.
+    /// `cx.headers().clone()`.
+    #[must_use]
+    pub fn on_connection_init_with_headers<F, R>(self, headers: HeaderMap, initializer: F) -> Self
+    where
+        F: FnOnce(HeaderMap, Value) -> R + Send + 'static,
+        R: Future<Output = Result<Data>> + Send + 'static,
+    {
+        self.on_connection_init(move |payload| initializer(headers, payload))
+    }
+
+    /// Specifies that a function converts the cookies of the upgrade request and the init
+    /// payload to data.
+    ///
+    /// The cookies must be taken from the `Context` before upgrading, e.g. with `cx.cookies()`,
+    /// which also reads private cookies.
+    #[must_use]
+    pub fn on_connection_init_with_cookies<F, R>(self, cookies: Cookies, initializer: F) -> Self
+    where
+        F: FnOnce(Cookies, Value) -> R + Send + 'static,
+        R: Future<Output = Result<Data>> + Send + 'static,
+    {
+        self.on_connection_init(move |payload| initializer(cookies, payload))
+    }
+
+    /// Adds the values to the data of the connection, replacing the values of the same type from
+    /// the initializer, e.g. the [`GraphQLData::get`] of the upgrade request.
+    #[must_use]
+    pub fn data(self, data: GraphQLData) -> Self {
+        Self { data, ..self }
+    }
+
+    /// Sets the max time to wait for the `connection_init` message, `None` waits forever.
+    ///
+    /// The connection is closed with `4408` when the timeout elapses.
+    #[must_use]
+    pub fn init_timeout(self, timeout: Option<Duration>) -> Self {
+        Self {
+            init_timeout: timeout,
+            ..self
+        }
+    }
+
+    /// Sets the interval of keepalive messages sent when nothing else was sent, `None` disables
+    /// them, which is the default.
+    ///
+    /// `ka` is sent for `graphql-ws`, `ping` for `graphql-transport-ws`.
+    #[must_use]
+    pub fn keepalive(self, interval: Option<Duration>) -> Self {
+        Self {
+            keepalive: interval,
+            ..self
+        }
+    }
+
+    /// Specifies that a function maps an error of the initializer to the close code and reason
+    /// of the connection, e.g. `4401` to prompt a new login.
+    ///
+    /// By default `graphql-transport-ws` closes with `1002`, and `graphql-ws` only sends a
+    /// `connection_error` message, which is still sent before closing.
+    #[must_use]
+    pub fn on_init_error<F>(self, f: F) -> Self
+    where
+        F: FnOnce(Error) -> (u16, String) + Send + 'static,
+    {
+        Self {
+            on_init_error: Some(Box::new(f)),
+            ..self
+        }
+    }
+
+    /// Specifies that a function maps the close code and default status text to the status text
+    /// sent, e.g. to localize it.
+    ///
+    /// It applies to the closes of the protocol, the init timeout and the shutdown, but not to
+    /// [`on_init_error`](Self::on_init_error) which sets its own text.
+    #[must_use]
+    pub fn close_status<F>(self, f: F) -> Self
+    where
+        F: Fn(u16, &str) -> String + Send + Sync + 'static,
+    {
+        Self {
+            close_status: Some(Arc::new(f)),
+            ..self
+        }
+    }
+
+    /// Specifies that a function is called with the errors of the connection and its operations,
+    /// e.g. to count them. The messages are still sent as without it.
+    ///
+    /// ```ignore
+    /// .on_error(|err: &SubscriptionError| tracing::warn!("subscription error: {}", err))
+    /// ```
+    #[must_use]
+    pub fn on_error<F>(self, f: F) -> Self
+    where
+        F: Fn(&SubscriptionError) + Send + Sync + 'static,
+    {
+        Self {
+            on_error: Some(Arc::new(f)),
+            ..self
+        }
+    }
+
+    /// Specifies that a function maps the errors of operations to a close code, e.g. `4500` for
+    /// errors the client shouldn't retry. The connection is closed after the error is sent,
+    /// with the message of the error as reason, truncated to 123 bytes.
+    ///
+    /// It's called with every error object sent, `None` keeps the connection open.
+    ///
+    /// ```ignore
+    /// .close_on_error(|err: &Value| {
+    ///     (err["extensions"]["code"] == "INTERNAL").then(|| 4500)
+    /// })
+    /// ```
+    #[must_use]
+    pub fn close_on_error<F>(self, f: F) -> Self
+    where
+        F: Fn(&Value) -> Option<u16> + Send + Sync + 'static,
+    {
+        Self {
+            close_on_error: Some(Arc::new(f)),
+            ..self
+        }
+    }
+
+    /// Specifies that a function maps the payload of a `ping` message of `graphql-transport-ws`
+    /// to the payload of its `pong`, e.g. the server time to measure the latency.
+    ///
+    /// Every `ping` is answered, by default with its own payload.
+    #[must_use]
+    pub fn on_ping<F>(self, f: F) -> Self
+    where
+        F: Fn(Option<Value>) -> Option<Value> + Send + Sync + 'static,
+    {
+        Self {
+            on_ping: Some(Arc::new(f)),
+            ..self
+        }
+    }
+
+    /// Sets the max time without any message received or sent, `None` disables it, which is
+    /// the default.
+    ///
+    /// The connection is closed with `1001` when the timeout elapses. Keepalive messages count as
+    /// sent messages, so the timeout should be shorter than their interval.
+    #[must_use]
+    pub fn idle_timeout(self, timeout: Option<Duration>) -> Self {
+        Self {
+            idle_timeout: timeout,
+            ..self
+        }
+    }
+
+    /// Buffers up to `capacity` messages waiting for a slow client, applying the policy when
+    /// the buffer is full.
+    ///
+    /// The subscriptions keep running while messages are sent. Without a buffer, which is the
+    /// default, they wait for each message to be sent.
+    #[must_use]
+    pub fn outbound_buffer(self, capacity: usize, policy: OverflowPolicy) -> Self {
+        Self {
+            outbound_buffer: Some((capacity, policy)),
+            ..self
+        }
+    }
+
+    /// Sets the max number of active subscriptions per connection.
+    ///
+    /// Operations over the limit get an `error` message, the active ones are kept alive.
+    #[must_use]
+    pub fn max_subscriptions(self, max: usize) -> Self {
+        Self {
+            max_subscriptions: max,
+            ..self
+        }
+    }
+
+    /// Sets a future which closes the connection when it resolves, e.g. on server shutdown.
+    ///
+    /// The ready messages are flushed, then the connection is closed with `1001`.
+    #[must_use]
+    pub fn shutdown<S>(self, shutdown: S) -> Self
+    where
+        S: Future<Output = ()> + Send + 'static,
+    {
+        Self {
+            shutdown: Some(Box::pin(shutdown)),
+            ..self
+        }
+    }
+
+    /// Serves the subscription until the connection is closed.
+    pub async fn serve(self) {
+        let (sink, stream) = self.websocket.split();
+
+        let protocol = self.protocol.0;
+        let max_subscriptions = self.max_subscriptions;
+        let state = Arc::new(ConnectionState::default());
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let on_error = self.on_error;
+        let on_ping = self.on_ping;
+
+        let input = stream
+            .scan((), {
+                let on_error = on_error.clone();
+                move |_, res| {
+                    future::ready(match res {
+                        Ok(msg) => Some(msg),
+                        Err(err) => {
+                            report(&on_error, SubscriptionError::Receive(err));
+                            None
+                        }
+                    })
+                }
+            })
+            .inspect({
+                let state = state.clone();
+                move |_| *state.last_received.lock().unwrap() = Some(time::Instant::now())
+            })
+            .filter(|msg| future::ready(msg.is_text() || msg.is_binary()))
+            .map(|msg| ClientMessage::from_bytes(msg.into_bytes()))
+            .filter_map({
+                let state = state.clone();
+                let tx = tx.clone();
+                move |msg| {
+                    future::ready(match msg {
+                        Ok(ClientMessage::ConnectionInit { payload }) => {
+                            state.initialized.store(true, Ordering::Relaxed);
+                            Some(Ok(ClientMessage::ConnectionInit { payload }))
+                        }
+                        Ok(ClientMessage::Start { id, payload }) => {
+                            let mut subscriptions = state.subscriptions.lock().unwrap();
+                            if !subscriptions.contains(&id)
+                                && subscriptions.len() >= max_subscriptions
+                            {
+                                let _ = tx.send(error_message(
+                                    protocol,
+                                    &id,
+                                    "Too many active subscriptions.",
+                                ));
+                                None
+                            } else {
+                                subscriptions.insert(id.clone());
+                                Some(Ok(ClientMessage::Start { id, payload }))
+                            }
+                        }
+                        Ok(ClientMessage::Stop { id }) => {
+                            state.subscriptions.lock().unwrap().remove(&id);
+                            Some(Ok(ClientMessage::Stop { id }))
+                        }
+                        Ok(ClientMessage::ConnectionTerminate) => {
+                            state.terminated.store(true, Ordering::Relaxed);
+                            Some(Ok(ClientMessage::ConnectionTerminate))
+                        }
+                        // `async_graphql` answers without payload, the `pong` is sent here.
+                        Ok(ClientMessage::Ping { payload })
+                            if protocol == WebSocketProtocols::GraphQLWS =>
+                        {
+                            let payload = match &on_ping {
+                                Some(f) => f(payload),
+                                None => payload,
+                            };
+                            let pong = match payload {
+                                Some(payload) => json!({ "type": "pong", "payload": payload }),
+                                None => json!({ "type": "pong" }),
+                            };
+                            let _ = tx.send(Message::text(pong.to_string()));
+                            None
+                        }
+                        msg => Some(msg),
+                    })
+                }
+            });
+
+        let initializer = {
+            let initializer = self.initializer;
+            let extra = self.data;
+            let state = state.clone();
+            move |payload| async move {
+                match initializer(payload).await {
+                    Ok((mut data, ack_payload)) => {
+                        extra.apply(&mut data);
+                        *state.ack_payload.lock().unwrap() = ack_payload;
+                        Ok(data)
+                    }
+                    Err(err) => {
+                        *state.init_error.lock().unwrap() = Some(err.clone());
+                        Err(err)
+                    }
+                }
+            }
+        };
+
+        let close_status = self.close_status;
+        let close = move |code: u16, status: &str| match &close_status {
+            Some(f) => close_message(code, f(code, status)),
+            None => close_message(code, status.to_owned()),
+        };
+
+        let mut on_init_error = self.on_init_error;
+        let mut stream = async_graphql::http::WebSocket::with_message_stream(
+            self.schema,
+            input,
+            initializer,
+            protocol,
+        )
+        .map({
+            let state = state.clone();
+            let close = close.clone();
+            let on_error = on_error.clone();
+            let close_on_error = self.close_on_error;
+            move |msg| {
+                // The init error is followed by a close of `graphql-transport-ws`, or a
+                // `connection_error` message of `graphql-ws`.
+                let init_error = match &msg {
+                    WsMessage::Close(1002, _) => true,
+                    WsMessage::Text(text) => text.starts_with(r#"{"type":"connection_error""#),
+                    _ => false,
+                };
+                let mapped = if init_error {
+                    let err = state.init_error.lock().unwrap().take();
+                    err.zip(on_init_error.take()).map(|(err, f)| f(err))
+                } else {
+                    None
+                };
+
+                match (msg, mapped) {
+                    (WsMessage::Close(..), Some((code, reason))) => close_message(code, reason),
+                    (WsMessage::Text(text), Some((code, reason))) => {
+                        let _ = tx.send(close_message(code, reason));
+                        Message::text(text)
+                    }
+                    (WsMessage::Text(text), None) => {
+                        if on_error.is_some() || close_on_error.is_some() {
+                            if let Some((id, errors)) = operation_errors(&text) {
+                                if let Some((code, reason)) = fatal_error(&close_on_error, &errors)
+                                {
+                                    *state.fatal_close.lock().unwrap() =
+                                        Some(close_message(code, reason));
+                                }
+                                report(&on_error, SubscriptionError::Operation { id, errors });
+                            }
+                        }
+                        if let Some(id) = completed_id(&text) {
+                            state.subscriptions.lock().unwrap().remove(&id);
+                        }
+                        if text == r#"{"type":"connection_ack"}"# {
+                            if let Some(payload) = state.ack_payload.lock().unwrap().take() {
+                                return Message::text(
+                                    json!({ "type": "connection_ack", "payload": payload })
+                                        .to_string(),
+                                );
+                            }
+                        }
+                        Message::text(text)
+                    }
+                    (WsMessage::Close(code, status), None) => close(code, &status),
+                }
+            }
+        });
+
+        let init_timeout = sleep_or_pending(self.init_timeout);
+        pin_mut!(init_timeout);
+        let mut waiting_init = true;
+
+        let shutdown = self.shutdown.unwrap_or_else(|| Box::pin(future::pending()));
+        pin_mut!(shutdown);
+
+        let keepalive_interval = self.keepalive.unwrap_or_default();
+        let keepalive = time::sleep(keepalive_interval);
+        pin_mut!(keepalive);
+
+        let idle_timeout = self.idle_timeout.unwrap_or_default();
+        let idle = time::sleep(idle_timeout);
+        pin_mut!(idle);
+        let mut last_sent = time::Instant::now();
+
+        let buffer = self
+            .outbound_buffer
+            .map(|(capacity, policy)| Outbound::new(capacity, policy));
+        let (mut outgoing, writer) = match &buffer {
+            Some(buffer) => (
+                Outgoing::Buffered(buffer, close(1008, "Too many pending messages")),
+                write(sink, buffer, on_error.clone()).left_future(),
+            ),
+            None => (
+                Outgoing::Direct(sink, on_error.clone()),
+                future::ready(()).right_future(),
+            ),
+        };
+
+        let serve = async move {
+            loop {
+                // The expansion of recent tokio versions uses `std::future::poll_fn`, tokio 1.12
+                // doesn't.
+                #[allow(clippy::incompatible_msrv)]
+                let item = tokio::select! {
+                    item = stream.next() => match item {
+                        Some(item) => item,
+                        // `connection_terminate` of `graphql-ws` ends the stream, the
+                        // connection is closed normally instead of being dropped.
+                        None if state.terminated.load(Ordering::Relaxed) => {
+                            close(1000, "Normal Closure")
+                        }
+                        None => break,
+                    },
+                    Some(item) = rx.recv() => item,
+                    _ = &mut shutdown => {
+                        while let Ok(item) = rx.try_recv() {
+                            outgoing.feed(item).await;
+                        }
+                        let mut close = close(1001, "Server shutting down");
+                        while let Some(Some(item)) = stream.next().now_or_never() {
+                            if item.is_close() {
+                                close = item;
+                                break;
+                            }
+                            outgoing.feed(item).await;
+                        }
+                        close
+                    }
+                    _ = &mut init_timeout, if waiting_init => {
+                        waiting_init = false;
+                        if state.initialized.load(Ordering::Relaxed) {
+                            continue;
+                        }
+                        close(4408, "Connection initialisation timeout")
+                    }
+                    _ = &mut keepalive, if self.keepalive.is_some() => {
+                        if !state.initialized.load(Ordering::Relaxed) {
+                            keepalive.as_mut().reset(time::Instant::now() + keepalive_interval);
+                            continue;
+                        }
+                        Message::text(match protocol {
+                            WebSocketProtocols::SubscriptionsTransportWS => r#"{"type":"ka"}"#,
+                            WebSocketProtocols::GraphQLWS => r#"{"type":"ping"}"#,
+                        })
+                    }
+                    _ = &mut idle, if self.idle_timeout.is_some() => {
+                        let last_received = *state.last_received.lock().unwrap();
+                        let last_activity = last_received.map_or(last_sent, |last| last.max(last_sent));
+                        if last_activity.elapsed() < idle_timeout {
+                            idle.as_mut().reset(last_activity + idle_timeout);
+                            continue;
+                        }
+                        close(1001, "Idle timeout")
+                    }
+                };
+
+                let is_close = item.is_close();
+                let mut open = outgoing.send(item).await;
+                // The error mapped to a close code is sent first, nothing else after it.
+                let fatal_close = state.fatal_close.lock().unwrap().take();
+                if let Some(close) = fatal_close.filter(|_| open && !is_close) {
+                    outgoing.send(close).await;
+                    open = false;
+                }
+                last_sent = time::Instant::now();
+                keepalive.as_mut().reset(last_sent + keepalive_interval);
+                // Nothing may be sent after a close frame, e.g. `4429` of `graphql-transport-ws`.
+                if is_close || !open {
+                    break;
+                }
+            }
+            outgoing.finish();
+        };
+
+        future::join(serve, writer).await;
+    }
+}
+
+/// What to do when the outbound buffer of a connection is full.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum OverflowPolicy {
+    /// Closes the connection with `1008`.
+    Close,
+    /// Drops the oldest pending message.
+    DropOldest,
+}
+
+/// An error of a subscription connection, see [`GraphQLSubscription::on_error`].
+#[derive(Debug)]
+pub enum SubscriptionError {
+    /// Receiving a message failed, the connection is closed.
+    Receive(viz_core::Error),
+    /// Sending a message failed.
+    Send(viz_core::Error),
+    /// An operation failed, or one of its results has errors.
+    Operation {
+        /// The id of the operation.
+        id: String,
+        /// The errors sent to the client.
+        errors: Value,
+    },
+}
+
+impl fmt::Display for SubscriptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubscriptionError::Receive(err) => write!(f, "failed to receive message: {}", err),
+            SubscriptionError::Send(err) => write!(f, "failed to send message: {}", err),
+            SubscriptionError::Operation { id, errors } => {
+                write!(f, "operation {} failed: {}", id, errors)
+            }
+        }
+    }
+}
+
+fn report(on_error: &Option<ErrorObserver>, err: SubscriptionError) {
+    if let Some(f) = on_error {
+        f(&err);
+    }
+}
+
+/// The messages sent to the client, directly or through the outbound buffer.
+enum Outgoing<'a> {
+    Direct(SplitSink<WebSocket, Message>, Option<ErrorObserver>),
+    Buffered(&'a Outbound, Message),
+}
+
+impl Outgoing<'_> {
+    /// Sends a message, returns `false` if the connection was closed by the overflow policy.
+    async fn send(&mut self, item: Message) -> bool {
+        match self {
+            Outgoing::Direct(sink, on_error) => {
+                if let Err(err) = sink.send(item).await {
+                    report(on_error, SubscriptionError::Send(err));
+                }
+                true
+            }
+            Outgoing::Buffered(buffer, overflow) => buffer.push(item, overflow),
+        }
+    }
+
+    /// Sends a message without flushing.
+    async fn feed(&mut self, item: Message) {
+        match self {
+            Outgoing::Direct(sink, on_error) => {
+                if let Err(err) = sink.feed(item).await {
+                    report(on_error, SubscriptionError::Send(err));
+                }
+            }
+            Outgoing::Buffered(buffer, overflow) => {
+                buffer.push(item, overflow);
+            }
+        }
+    }
+
+    /// Lets the writer stop once the pending messages are sent.
+    fn finish(&self) {
+        if let Outgoing::Buffered(buffer, _) = self {
+            buffer.closed.store(true, Ordering::Relaxed);
+            buffer.notify.notify_one();
+        }
+    }
+}
+
+/// A bounded buffer of the messages waiting for a slow client.
+struct Outbound {
+    capacity: usize,
+    policy: OverflowPolicy,
+    queue: Mutex<VecDeque<Message>>,
+    notify: Notify,
+    closed: AtomicBool,
+}
+
+impl Outbound {
+    fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            policy,
+            queue: Mutex::default(),
+            notify: Notify::new(),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// Queues a message, or the overflow close message in place of the pending ones.
+    fn push(&self, item: Message, overflow: &Message) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        let full = queue.len() >= self.capacity && !item.is_close();
+        let open = match self.policy {
+            OverflowPolicy::Close if full => {
+                queue.clear();
+                queue.push_back(overflow.clone());
+                false
+            }
+            OverflowPolicy::DropOldest if full => {
+                queue.pop_front();
+                queue.push_back(item);
+                true
+            }
+            _ => {
+                queue.push_back(item);
+                true
+            }
+        };
+        drop(queue);
+        self.notify.notify_one();
+        open
+    }
+
+    /// Waits for the next pending message, `None` once finished.
+    async fn pop(&self) -> Option<Message> {
+        loop {
+            if let Some(item) = self.queue.lock().unwrap().pop_front() {
+                return Some(item);
+            }
+            if self.closed.load(Ordering::Relaxed) {
+                return None;
+            }
+            self.notify.notified().await;
+        }
+    }
+}
+
+/// Sends the buffered messages, until a close frame.
+async fn write(
+    mut sink: SplitSink<WebSocket, Message>,
+    buffer: &Outbound,
+    on_error: Option<ErrorObserver>,
+) {
+    while let Some(item) = buffer.pop().await {
+        let is_close = item.is_close();
+        if let Err(err) = sink.send(item).await {
+            report(&on_error, SubscriptionError::Send(err));
+        }
+        if is_close {
+            return;
+        }
+    }
+}
+
+/// State shared by the incoming and outgoing messages of a connection.
+#[derive(Default)]
+struct ConnectionState {
+    initialized: AtomicBool,
+    terminated: AtomicBool,
+    subscriptions: Mutex<HashSet<String>>,
+    init_error: Mutex<Option<Error>>,
+    ack_payload: Mutex<Option<Value>>,
+    last_received: Mutex<Option<time::Instant>>,
+    fatal_close: Mutex<Option<Message>>,
+}
+
+/// Creates an `error` message for the operation.
+fn error_message(protocol: WebSocketProtocols, id: &str, message: &str) -> Message {
+    let payload = match protocol {
+        WebSocketProtocols::SubscriptionsTransportWS => json!({ "message": message }),
+        WebSocketProtocols::GraphQLWS => json!([{ "message": message }]),
+    };
+    Message::text(json!({ "type": "error", "id": id, "payload": payload }).to_string())
+}
+
+/// Creates a close message, the reason is truncated to the 123 bytes allowed by the protocol.
+fn close_message(code: u16, mut reason: String) -> Message {
+    if reason.len() > 123 {
+        let mut end = 123;
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        reason.truncate(end);
+    }
+    Message::close_with(code, Cow::from(reason))
+}
+
+/// Gets the close code and reason of the first error mapped to a close code.
+fn fatal_error(close_on_error: &Option<FatalErrorMapper>, errors: &Value) -> Option<(u16, String)> {
+    let f = close_on_error.as_ref()?;
+    let errors = match errors {
+        Value::Array(errors) => errors.as_slice(),
+        error => std::slice::from_ref(error),
+    };
+    errors.iter().find_map(|error| {
+        let reason = error["message"].as_str().unwrap_or_default().to_owned();
+        f(error).map(|code| (code, reason))
+    })
+}
+
+/// Gets the id of a `complete` message sent by `async_graphql`.
+fn completed_id(text: &str) -> Option<String> {
+    // The `type` tag is always serialized first, so other messages are skipped cheaply.
+    if !text.starts_with(r#"{"type":"complete""#) {
+        return None;
+    }
+    json::from_str::<Value>(text)
+        .ok()?
+        .get("id")?
+        .as_str()
+        .map(ToOwned::to_owned)
+}
+
+/// Gets the id and errors of an `error` message, or of a result with errors, sent by
+/// `async_graphql`.
+fn operation_errors(text: &str) -> Option<(String, Value)> {
+    let is_error = text.starts_with(r#"{"type":"error""#);
+    if !is_error && !text.contains(r#""errors":"#) {
+        return None;
+    }
+    let mut msg = json::from_str::<Value>(text).ok()?;
+    let id = msg.get("id")?.as_str()?.to_owned();
+    let errors = if is_error {
+        msg.get_mut("payload")?.take()
+    } else {
+        msg.get_mut("payload")?.get_mut("errors")?.take()
+    };
+    Some((id, errors))
+}
+
+async fn sleep_or_pending(duration: Option<Duration>) {
+    match duration {
+        Some(duration) => time::sleep(duration).await,
+        None => future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::{EmptyMutation, Object, Subscription};
+    use viz_utils::futures::{stream, Stream};
+
+    use super::*;
+    use crate::test_util::{TestClient, TestMessage};
+
+    struct Query;
+
+    #[Object]
+    impl Query {
+        async fn value(&self) -> i32 {
+            1
+        }
+    }
+
+    struct Subscription;
+
+    #[Subscription]
+    impl Subscription {
+        async fn values(&self) -> impl Stream<Item = i32> {
+            stream::iter(0..2)
+        }
+    }
+
+    type TestSubscription = GraphQLSubscription<Query, EmptyMutation, Subscription>;
+
+    async fn connect<F>(protocol: WebSocketProtocols, configure: F) -> TestClient
+    where
+        F: FnOnce(TestSubscription) -> TestSubscription + Send + 'static,
+    {
+        let protocol = SecWebsocketProtocol(protocol);
+        TestClient::connect(protocol, move |websocket| {
+            let schema = Schema::new(Query, EmptyMutation, Subscription);
+            configure(GraphQLSubscription::new(websocket, schema, protocol)).serve()
+        })
+        .await
+    }
+
+    fn message(value: Value) -> Option<TestMessage> {
+        Some(TestMessage::Json(value))
+    }
+
+    #[tokio::test]
+    async fn legacy_protocol_acks_connection_init() {
+        let mut client = connect(WebSocketProtocols::SubscriptionsTransportWS, |s| s).await;
+
+        client.send(json!({ "type": "connection_init" })).await;
+        assert_eq!(
+            client.recv().await,
+            message(json!({ "type": "connection_ack" }))
+        );
+    }
+
+    #[tokio::test]
+    async fn graphql_transport_ws_subscribes() {
+        let mut client = connect(WebSocketProtocols::GraphQLWS, |s| s).await;
+
+        client.send(json!({ "type": "connection_init" })).await;
+        assert_eq!(
+            client.recv().await,
+            message(json!({ "type": "connection_ack" }))
+        );
+
+        client
+            .send(json!({
+                "type": "subscribe",
+                "id": "1",
+                "payload": { "query": "subscription { values }" },
+            }))
+            .await;
+        for value in 0..2 {
+            assert_eq!(
+                client.recv().await,
+                message(json!({
+                    "type": "next",
+                    "id": "1",
+                    "payload": { "data": { "values": value } },
+                }))
+            );
+        }
+        assert_eq!(
+            client.recv().await,
+            message(json!({ "type": "complete", "id": "1" }))
+        );
+
+        client.close(1000, "").await;
+        assert_eq!(
+            client.recv().await,
+            Some(TestMessage::Close(1000, String::new()))
+        );
+    }
+
+    fn reject_init(subscription: TestSubscription) -> TestSubscription {
+        subscription
+            .on_connection_init(|_| async { Err(Error::new("invalid token")) })
+            .on_init_error(|err| (4401, format!("Unauthorized: {}", err.message)))
+    }
+
+    #[tokio::test]
+    async fn rejected_init_closes_with_the_mapped_code() {
+        let mut client = connect(WebSocketProtocols::GraphQLWS, reject_init).await;
+
+        client.send(json!({ "type": "connection_init" })).await;
+        assert_eq!(
+            client.recv().await,
+            Some(TestMessage::Close(
+                4401,
+                "Unauthorized: invalid token".to_owned()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn long_close_reasons_are_truncated() {
+        let mut client = connect(WebSocketProtocols::GraphQLWS, |s| {
+            s.on_connection_init(|_| async { Err(Error::new("é".repeat(100))) })
+        })
+        .await;
+
+        client.send(json!({ "type": "connection_init" })).await;
+        assert_eq!(
+            client.recv().await,
+            Some(TestMessage::Close(1002, "é".repeat(61)))
+        );
+    }
+
+    #[tokio::test]
+    async fn rejected_init_of_legacy_protocol_sends_the_error_first() {
+        let mut client = connect(WebSocketProtocols::SubscriptionsTransportWS, reject_init).await;
+
+        client.send(json!({ "type": "connection_init" })).await;
+        assert_eq!(
+            client.recv().await,
+            message(json!({
+                "type": "connection_error",
+                "payload": { "message": "invalid token" },
+            }))
+        );
+        assert_eq!(
+            client.recv().await,
+            Some(TestMessage::Close(
+                4401,
+                "Unauthorized: invalid token".to_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn confirmed_protocol_round_trips() {
+        for protocol in [
+            WebSocketProtocols::GraphQLWS,
+            WebSocketProtocols::SubscriptionsTransportWS,
+        ] {
+            let resp = SecWebsocketProtocol(protocol).confirm(Response::default());
+            let values: Vec<_> = resp
+                .headers()
+                .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+                .iter()
+                .collect();
+            assert_eq!(values, [protocol.sec_websocket_protocol()]);
+            assert_eq!(
+                resp.headers().typed_get::<SecWebsocketProtocol>(),
+                Some(SecWebsocketProtocol(protocol))
+            );
+        }
+    }
+
+    #[test]
+    fn first_supported_offered_protocol_is_selected() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static("unknown, graphql-transport-ws, graphql-ws"),
+        );
+        assert_eq!(
+            headers.typed_get::<SecWebsocketProtocol>(),
+            Some(SecWebsocketProtocol(WebSocketProtocols::GraphQLWS))
+        );
+
+        headers.insert(
+            header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static("unknown"),
+        );
+        assert!(headers.typed_try_get::<SecWebsocketProtocol>().is_err());
+    }
+
+    #[tokio::test]
+    async fn connection_terminate_closes_normally() {
+        let errors = Arc::new(AtomicBool::new(false));
+        let observed = errors.clone();
+        let mut client = connect(WebSocketProtocols::SubscriptionsTransportWS, move |s| {
+            s.on_error(move |_| observed.store(true, Ordering::Relaxed))
+        })
+        .await;
+
+        client.send(json!({ "type": "connection_init" })).await;
+        assert_eq!(
+            client.recv().await,
+            message(json!({ "type": "connection_ack" }))
+        );
+
+        client.send(json!({ "type": "connection_terminate" })).await;
+        assert_eq!(
+            client.recv().await,
+            Some(TestMessage::Close(1000, "Normal Closure".to_owned()))
+        );
+        assert_eq!(client.recv().await, None);
+        assert!(!errors.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn pings_are_answered_with_their_payload() {
+        let mut client = connect(WebSocketProtocols::GraphQLWS, |s| s).await;
+
+        client.send(json!({ "type": "connection_init" })).await;
+        assert_eq!(
+            client.recv().await,
+            message(json!({ "type": "connection_ack" }))
+        );
+
+        client
+            .send(json!({ "type": "ping", "payload": { "sent": 42 } }))
+            .await;
+        assert_eq!(
+            client.recv().await,
+            message(json!({ "type": "pong", "payload": { "sent": 42 } }))
+        );
+
+        client.send(json!({ "type": "ping" })).await;
+        assert_eq!(client.recv().await, message(json!({ "type": "pong" })));
+    }
+
+    #[tokio::test]
+    async fn pong_payloads_are_mapped() {
+        let mut client = connect(WebSocketProtocols::GraphQLWS, |s| {
+            s.on_ping(|payload| Some(json!({ "ping": payload, "time": 1 })))
+        })
+        .await;
+
+        client.send(json!({ "type": "connection_init" })).await;
+        assert_eq!(
+            client.recv().await,
+            message(json!({ "type": "connection_ack" }))
+        );
+
+        client
+            .send(json!({ "type": "ping", "payload": { "sent": 42 } }))
+            .await;
+        assert_eq!(
+            client.recv().await,
+            message(json!({
+                "type": "pong",
+                "payload": { "ping": { "sent": 42 }, "time": 1 },
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_protocol_is_rejected_by_default() {
+        let mut cx = Context::from(viz_core::http::Request::new(Default::default()));
+        let resp = SecWebsocketProtocol::extract(&mut cx).await.unwrap_err();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let mut cx = Context::from(viz_core::http::Request::new(Default::default()));
+        cx.extensions_mut().insert(
+            GraphQLConfig::new()
+                .default_websocket_protocol(Some(WebSocketProtocols::SubscriptionsTransportWS)),
+        );
+        assert_eq!(
+            SecWebsocketProtocol::extract(&mut cx).await.ok(),
+            Some(SecWebsocketProtocol(
+                WebSocketProtocols::SubscriptionsTransportWS
+            ))
+        );
     }
 }
@@ -0,0 +1,173 @@
+//! Utilities to test subscription handlers.
+//!
+//! [`TestClient`] connects to a handler over an in-memory stream, so the frames of the
+//! WebSocket protocols can be sent and asserted without a server:
+//!
+//! ```ignore
+//! let protocol = SecWebsocketProtocol(WebSocketProtocols::GraphQLWS);
+//! let mut client = TestClient::connect(protocol, move |websocket| {
+//!     graphql_subscription(websocket, schema, protocol)
+//! })
+//! .await;
+//!
+//! client.send(json!({ "type": "connection_init" })).await;
+//! assert_eq!(client.recv().await, Some(TestMessage::Json(json!({ "type": "connection_ack" }))));
+//! ```
+
+use std::{convert::Infallible, future::Future, sync::Mutex};
+
+use hyper::{server::conn::Http, service::service_fn};
+use tokio::io::{self, DuplexStream};
+use tokio_tungstenite::{
+    tungstenite::{
+        client::IntoClientRequest,
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Message,
+    },
+    WebSocketStream,
+};
+
+use viz_core::{
+    http::{self, header, HeaderValue},
+    ws::WebSocket,
+    Context, Response,
+};
+use viz_utils::{
+    futures::{SinkExt, StreamExt},
+    serde::json::{self, Value},
+};
+
+use crate::SecWebsocketProtocol;
+
+/// The size of the in-memory stream between the client and the handler.
+const MAX_BUF_SIZE: usize = 64 * 1024;
+
+/// A message received by a [`TestClient`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum TestMessage {
+    /// A text frame, decoded as JSON.
+    Json(Value),
+    /// A close frame, with its code and reason.
+    ///
+    /// The code is `1005` if the frame has no code.
+    Close(u16, String),
+}
+
+/// A WebSocket client connected to a subscription handler over an in-memory stream.
+///
+/// It's only meant for tests, so it panics when the connection fails.
+pub struct TestClient {
+    stream: WebSocketStream<DuplexStream>,
+}
+
+impl TestClient {
+    /// Connects to the handler, the upgrade request offers the protocol, which is confirmed on
+    /// the upgrade response.
+    pub async fn connect<F, R>(protocol: SecWebsocketProtocol, handler: F) -> Self
+    where
+        F: FnOnce(WebSocket) -> R + Send + 'static,
+        R: Future<Output = ()> + Send + 'static,
+    {
+        let mut request = "ws://localhost/"
+            .into_client_request()
+            .expect("invalid upgrade request");
+        request.headers_mut().insert(
+            header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static(protocol.0.sec_websocket_protocol()),
+        );
+
+        Self::connect_with_request(request, move |cx| match cx.ws() {
+            Ok(ws) => protocol.confirm(ws.on_upgrade(handler)),
+            Err(resp) => resp,
+        })
+        .await
+    }
+
+    /// Connects to the handler with the upgrade request, e.g. with the headers read by
+    /// [`GraphQLSubscription::on_connection_init_with_headers`].
+    ///
+    /// The handler receives the context of the request, and must upgrade the connection.
+    ///
+    /// [`GraphQLSubscription::on_connection_init_with_headers`]:
+    /// crate::GraphQLSubscription::on_connection_init_with_headers
+    pub async fn connect_with_request<F>(request: http::Request<()>, handler: F) -> Self
+    where
+        F: FnOnce(&mut Context) -> Response + Send + 'static,
+    {
+        let (client, server) = io::duplex(MAX_BUF_SIZE);
+
+        // The handler is only called once, by the upgrade request.
+        let handler = Mutex::new(Some(handler));
+        let service = service_fn(move |req| {
+            let handler = handler
+                .lock()
+                .unwrap()
+                .take()
+                .expect("the connection is already upgraded");
+            let mut cx = Context::from(req);
+            let resp = handler(&mut cx);
+            async move { Ok::<_, Infallible>(http::Response::from(resp)) }
+        });
+        tokio::spawn(async move {
+            let _ = Http::new()
+                .http1_only(true)
+                .serve_connection(server, service)
+                .with_upgrades()
+                .await;
+        });
+
+        let (stream, _) = tokio_tungstenite::client_async(request, client)
+            .await
+            .expect("failed to upgrade the connection");
+
+        Self { stream }
+    }
+
+    /// Sends a text frame of the JSON value.
+    pub async fn send(&mut self, message: Value) {
+        self.send_text(message.to_string()).await;
+    }
+
+    /// Sends a text frame, e.g. an invalid message.
+    pub async fn send_text(&mut self, text: impl Into<String>) {
+        self.stream
+            .send(Message::Text(text.into()))
+            .await
+            .expect("failed to send the message");
+    }
+
+    /// Sends a close frame with the code and reason.
+    pub async fn close(&mut self, code: u16, reason: &str) {
+        let frame = CloseFrame {
+            code: CloseCode::from(code),
+            reason: reason.to_owned().into(),
+        };
+        self.stream
+            .send(Message::Close(Some(frame)))
+            .await
+            .expect("failed to send the close frame");
+    }
+
+    /// Receives the next text or close frame, skipping pings and pongs.
+    ///
+    /// Returns `None` once the connection is closed.
+    pub async fn recv(&mut self) -> Option<TestMessage> {
+        loop {
+            match self.stream.next().await? {
+                Ok(Message::Text(text)) => {
+                    let value = json::from_str(&text).expect("the message is not JSON");
+                    return Some(TestMessage::Json(value));
+                }
+                Ok(Message::Close(frame)) => {
+                    return Some(match frame {
+                        Some(frame) => TestMessage::Close(frame.code.into(), frame.reason.into()),
+                        None => TestMessage::Close(1005, String::new()),
+                    });
+                }
+                Ok(Message::Binary(_)) => panic!("unexpected binary message"),
+                Ok(_) => {}
+                Err(_) => return None,
+            }
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use std::{
+    fs::File,
+    io::{self, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+use bytes::Bytes;
+use tokio::task;
+use viz_utils::futures::{
+    future::{self, BoxFuture},
+    stream::{BoxStream, TryStreamExt},
+};
+
+/// The content of an uploaded file, streamed from the multipart body.
+///
+/// It fails once the file is larger than `MultipartOptions::max_file_size`, or than what's left
+/// of `GraphQLConfig::max_uploads_size`.
+pub type UploadStream<'a> = BoxStream<'a, io::Result<Bytes>>;
+
+/// A storage of the uploaded files of multipart requests, e.g. streaming them to an object
+/// store.
+///
+/// `async_graphql::UploadValue` holds a `std::fs::File`, so a storage must still return one to
+/// the resolvers, e.g. a file holding the key of the stored object. [`TempFileStorage`] is used
+/// by default.
+///
+/// A storage cleans up what it wrote itself when `store` fails. The files already stored when
+/// a request fails, e.g. without a `map` part, or when it's rejected once received, e.g. over
+/// its rate limit, are given to [`discard`](Self::discard) before the rejection is returned;
+/// once the request is extracted they're owned by its uploads.
+pub trait UploadStorage: Send + Sync + 'static {
+    /// Stores an uploaded file, returns the file given to the resolvers.
+    fn store<'a>(
+        &'a self,
+        filename: &'a str,
+        content_type: Option<&'a str>,
+        content: UploadStream<'a>,
+    ) -> BoxFuture<'a, io::Result<File>>;
+
+    /// Discards a stored file of a request which failed, e.g. to delete the stored object.
+    ///
+    /// The file is dropped by default, which deletes the temp files of [`TempFileStorage`].
+    fn discard(&self, file: File) -> BoxFuture<'_, io::Result<()>> {
+        drop(file);
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+/// Stores the uploaded files in temp files, which are deleted when dropped.
+///
+/// Every upload is written to a temp file, as `UploadValue::content` is a `std::fs::File`. The
+/// content is buffered up to the [`write_buffer_size`](Self::write_buffer_size) between writes,
+/// so a file under it is written at once.
+#[derive(Debug, Clone)]
+pub struct TempFileStorage {
+    dir: Option<PathBuf>,
+    write_buffer_size: usize,
+}
+
+impl Default for TempFileStorage {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            write_buffer_size: Self::DEFAULT_WRITE_BUFFER_SIZE,
+        }
+    }
+}
+
+impl TempFileStorage {
+    /// Size of the content buffered between writes to the temp file, defaults to 64 KiB.
+    pub const DEFAULT_WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+    /// Creates a storage in the OS temp directory.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a storage in the directory.
+    #[must_use]
+    pub fn in_dir(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: Some(dir.into()),
+            ..Self::default()
+        }
+    }
+
+    /// Sets the size of the content buffered between writes to the temp file.
+    #[must_use]
+    pub fn write_buffer_size(self, size: usize) -> Self {
+        Self {
+            write_buffer_size: size,
+            ..self
+        }
+    }
+
+    /// Writes the buffer to the file, created first if it's `None`, on a blocking thread.
+    ///
+    /// The file is rewound once the last buffer is written, the resolvers read it from its start.
+    async fn write(
+        &self,
+        file: Option<File>,
+        mut buffer: Vec<u8>,
+        last: bool,
+    ) -> io::Result<(File, Vec<u8>)> {
+        let dir = self.dir.clone();
+        task::spawn_blocking(move || {
+            let mut file = match file {
+                Some(file) => file,
+                None => match dir {
+                    Some(dir) => tempfile::tempfile_in(dir),
+                    None => tempfile::tempfile(),
+                }?,
+            };
+            file.write_all(&buffer)?;
+            buffer.clear();
+            if last {
+                file.seek(SeekFrom::Start(0))?;
+            }
+            Ok((file, buffer))
+        })
+        .await
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?
+    }
+}
+
+impl UploadStorage for TempFileStorage {
+    fn store<'a>(
+        &'a self,
+        _filename: &'a str,
+        _content_type: Option<&'a str>,
+        mut content: UploadStream<'a>,
+    ) -> BoxFuture<'a, io::Result<File>> {
+        Box::pin(async move {
+            // The file is written on blocking threads, so the writes don't stall the runtime.
+            let mut buffer = Vec::new();
+            let mut file = None;
+            while let Some(chunk) = content.try_next().await? {
+                buffer.extend_from_slice(&chunk);
+                if buffer.len() > self.write_buffer_size {
+                    let (written, emptied) = self.write(file.take(), buffer, false).await?;
+                    file = Some(written);
+                    buffer = emptied;
+                }
+            }
+
+            Ok(self.write(file, buffer, true).await?.0)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use viz_utils::futures::stream::{self, StreamExt};
+
+    use super::*;
+
+    async fn store(storage: &TempFileStorage, chunks: &[&'static [u8]]) -> Vec<u8> {
+        let content = stream::iter(chunks.iter().map(|chunk| Ok(Bytes::from(*chunk)))).boxed();
+        let mut file = storage.store("a.txt", None, content).await.unwrap();
+
+        let mut stored = Vec::new();
+        file.read_to_end(&mut stored).unwrap();
+        stored
+    }
+
+    #[tokio::test]
+    async fn files_are_stored_from_their_start() {
+        let storage = TempFileStorage::new();
+        assert_eq!(
+            store(&storage, &[b"hello", b" world"]).await,
+            b"hello world"
+        );
+        assert_eq!(store(&storage, &[]).await, b"");
+    }
+
+    #[tokio::test]
+    async fn files_over_the_buffer_size_are_stored_whole() {
+        let storage = TempFileStorage::new().write_buffer_size(4);
+        assert_eq!(
+            store(&storage, &[b"hel", b"lo", b" wo", b"rld"]).await,
+            b"hello world"
+        );
+    }
+}